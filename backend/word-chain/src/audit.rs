@@ -0,0 +1,180 @@
+use crate::credentials::session::Device;
+use chrono::{DateTime, Utc};
+use crate::openapi::Schema;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use tokio_postgres::Client;
+
+/// Security-relevant events, recorded in `audit_log`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    AccountCreated,
+    AccountDeleted,
+    LoginSucceeded,
+    LoginFailed,
+    /// Login attempted while locked out by too many failures
+    LoginThrottled,
+    /// Password was right, but the second factor is pending
+    LoginChallenged,
+    SecondFactorFailed,
+    Logout,
+    TokenRefreshed,
+    /// Consumed refresh token was presented again, and its session has been revoked
+    RefreshTokenReused,
+    SessionRevoked,
+    RoleGranted,
+    RoleRevoked,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    /// TOTP enrollment was confirmed
+    SecondFactorEnabled,
+    SecondFactorDisabled,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::AccountCreated => "account_created",
+            AuditEvent::AccountDeleted => "account_deleted",
+            AuditEvent::LoginSucceeded => "login_succeeded",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::LoginThrottled => "login_throttled",
+            AuditEvent::LoginChallenged => "login_challenged",
+            AuditEvent::SecondFactorFailed => "second_factor_failed",
+            AuditEvent::Logout => "logout",
+            AuditEvent::TokenRefreshed => "token_refreshed",
+            AuditEvent::RefreshTokenReused => "refresh_token_reused",
+            AuditEvent::SessionRevoked => "session_revoked",
+            AuditEvent::RoleGranted => "role_granted",
+            AuditEvent::RoleRevoked => "role_revoked",
+            AuditEvent::ApiKeyCreated => "api_key_created",
+            AuditEvent::ApiKeyRevoked => "api_key_revoked",
+            AuditEvent::SecondFactorEnabled => "second_factor_enabled",
+            AuditEvent::SecondFactorDisabled => "second_factor_disabled",
+        }
+    }
+}
+
+/// How an account signed in, recorded as `method` of login events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginMethod<'a> {
+    Password,
+    /// Second step of login, by TOTP or a recovery code
+    Totp,
+    /// OpenID Connect of the provider
+    Oidc(&'a str),
+}
+
+impl Display for LoginMethod<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginMethod::Password => write!(f, "password"),
+            LoginMethod::Totp => write!(f, "totp"),
+            LoginMethod::Oidc(provider) => write!(f, "oidc:{}", provider),
+        }
+    }
+}
+
+/// Entry of `audit_log`.
+///
+/// `detail` is what the event was done to, if anything but the account itself:
+/// the session (refresh-token family) of `logout`, `token_refreshed`, `refresh_token_reused` and `session_revoked`,
+/// the role of `role_granted` and `role_revoked`, and the key prefix of `api_key_created` and `api_key_revoked`.
+///
+/// NOTE: Never put passwords, tokens, keys or codes in `detail`; it is shown to admins as is.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    id: i64,
+    occurred_at: String,
+    event: String,
+    account: Option<String>,
+    /// Account which made the event happen to `account`, if it is another one
+    actor: Option<String>,
+    /// [`LoginMethod`] of login events
+    method: Option<String>,
+    address: Option<String>,
+    user_agent: Option<String>,
+    detail: Option<String>,
+}
+
+impl Schema for AuditEntry {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "occurred_at": { "type": "string" },
+                "event": { "type": "string" },
+                "account": { "type": ["string", "null"] },
+                "actor": { "type": ["string", "null"] },
+                "method": { "type": ["string", "null"], "description": "`password`, `totp` or `oidc:{provider}`, of login events" },
+                "address": { "type": ["string", "null"] },
+                "user_agent": { "type": ["string", "null"] },
+                "detail": { "type": ["string", "null"] }
+            },
+            "required": ["id", "occurred_at", "event", "account", "actor", "method", "address", "user_agent", "detail"]
+        })
+    }
+}
+
+/// Append-only log of [`AuditEvent`]s; the table rejects updates and deletions by itself
+pub struct Audit;
+
+impl Audit {
+    /// Records `event`; failure is only reported, since the action itself has already happened
+    pub async fn record(event: AuditEvent, account: Option<&str>, device: &Device, detail: Option<&str>, client: &Client) {
+        Self::insert(event, account, None, None, device, detail, client).await
+    }
+
+    /// Records `event` which `actor` made happen to `account`, e.g. by administration
+    pub async fn record_by(event: AuditEvent, actor: Option<&str>, account: Option<&str>, device: &Device, detail: Option<&str>, client: &Client) {
+        Self::insert(event, account, actor, None, device, detail, client).await
+    }
+
+    /// Records login `event` of `account` by `method`
+    pub async fn record_login(event: AuditEvent, account: &str, method: LoginMethod<'_>, device: &Device, client: &Client) {
+        Self::insert(event, Some(account), None, Some(&method.to_string()), device, None, client).await
+    }
+
+    async fn insert(event: AuditEvent, account: Option<&str>, actor: Option<&str>, method: Option<&str>, device: &Device, detail: Option<&str>, client: &Client) {
+        if let Err(e) = client.execute(r#"
+            INSERT INTO audit_log (event, account, actor, method, address, user_agent, detail)
+            VALUES ($1, $2, $3, $4, $5, $6, $7);
+            "#,
+            &[&event.as_str(), &account, &actor, &method, &device.address(), &device.user_agent(), &detail]).await {
+            eprintln!("Failed to record audit event `{}`: {}", event.as_str(), e);
+        }
+    }
+
+    /// At most `limit` entries older than `before`, the newest first
+    pub async fn query(before: Option<i64>, limit: i64, account: Option<&str>, event: Option<&str>, client: &Client) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+        let rows = client.query(r#"
+            SELECT id, occurred_at, event, account, actor, method, address, user_agent, detail FROM audit_log
+            WHERE ($1::BIGINT IS NULL OR id < $1)
+              AND ($2::TEXT IS NULL OR account = $2)
+              AND ($3::TEXT IS NULL OR event = $3)
+            ORDER BY id DESC
+            LIMIT $4;
+            "#,
+            &[&before, &account, &event, &limit]).await?;
+
+        Ok(rows.into_iter().map(|row| AuditEntry {
+            id: row.get(0),
+            occurred_at: row.get::<_, DateTime<Utc>>(1).to_rfc3339(),
+            event: row.get(2),
+            account: row.get(3),
+            actor: row.get(4),
+            method: row.get(5),
+            address: row.get(6),
+            user_agent: row.get(7),
+            detail: row.get(8),
+        }).collect())
+    }
+}
+
+impl AuditEntry {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+}
@@ -1,3 +1,12 @@
 pub mod jwt;
+pub mod keyring;
 pub mod basic;
-pub mod tokens;
\ No newline at end of file
+pub mod tokens;
+pub mod totp;
+pub mod oidc;
+pub mod throttle;
+pub mod login;
+pub mod session;
+pub mod csrf;
+pub mod api_key;
+pub mod rbac;
\ No newline at end of file
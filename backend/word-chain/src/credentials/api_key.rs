@@ -0,0 +1,157 @@
+use crate::credentials::csrf::is_safe;
+use crate::encrypt::{constant_time_eq, Sha256};
+use chrono::{DateTime, Utc};
+use hyper::Method;
+use rand::distributions::{Alphanumeric, DistString};
+use crate::openapi::Schema;
+use serde::Serialize;
+use std::error::Error;
+use tokio_postgres::Client;
+
+const API_KEY_PREFIX: &str = "wc_";
+
+/// Scopes a key can be limited to; a key without scopes has all of them
+pub const SCOPES: [&str; 2] = ["read", "write"];
+
+/// Scope required for a request with `method`
+fn required_scope(method: &Method) -> &'static str {
+    if is_safe(method) { "read" } else { "write" }
+}
+
+/// Whether `token` of `Authorization: Bearer` is an API key rather than an access token
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+/// Splits `wc_{prefix}_{secret}` into prefix and secret
+fn parse(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(API_KEY_PREFIX)?.split_once('_')
+}
+
+/// Prefix of `key`, which tells it apart without its secret
+pub fn prefix_of(key: &str) -> Option<&str> {
+    parse(key).map(|(prefix, _)| prefix)
+}
+
+/// Long-lived credential of an account for bots, stored in `api_keys`.
+///
+/// Only the hash of its secret is stored; the prefix is kept to tell keys apart.
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    prefix: String,
+    name: String,
+    scopes: Option<Vec<String>>,
+    expires_at: Option<String>,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+impl Schema for ApiKey {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "prefix": { "type": "string" },
+                "name": { "type": "string" },
+                "scopes": { "type": ["array", "null"], "items": { "enum": SCOPES } },
+                "expires_at": { "type": ["string", "null"] },
+                "created_at": { "type": "string" },
+                "last_used_at": { "type": ["string", "null"] }
+            },
+            "required": ["prefix", "name", "scopes", "expires_at", "created_at", "last_used_at"]
+        })
+    }
+}
+
+impl ApiKey {
+    /// Returns the key, which is never shown again
+    pub async fn create(who: &str, name: &str, scopes: Option<Vec<String>>, expires_at: Option<DateTime<Utc>>, client: &Client) -> Result<String, Box<dyn Error>> {
+        let prefix = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+        let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+        // NOTE: Secret is random enough that a fast hash can't be brute-forced
+        client.execute(r#"
+            INSERT INTO api_keys (prefix, account, name, hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6);
+            "#,
+            &[&prefix, &who, &name, &Sha256::hash(&secret), &scopes, &expires_at]).await?;
+
+        Ok(format!("{}{}_{}", API_KEY_PREFIX, prefix, secret))
+    }
+
+    pub async fn list(who: &str, client: &Client) -> Result<Vec<Self>, Box<dyn Error>> {
+        let rows = client.query(r#"
+            SELECT prefix, name, scopes, expires_at, created_at, last_used_at FROM api_keys
+            WHERE account = $1 ORDER BY created_at;
+            "#,
+            &[&who]).await?;
+
+        Ok(rows.into_iter().map(|row| Self {
+            prefix: row.get(0),
+            name: row.get(1),
+            scopes: row.get(2),
+            expires_at: row.get::<_, Option<DateTime<Utc>>>(3).map(|t| t.to_rfc3339()),
+            created_at: row.get::<_, DateTime<Utc>>(4).to_rfc3339(),
+            last_used_at: row.get::<_, Option<DateTime<Utc>>>(5).map(|t| t.to_rfc3339()),
+        }).collect())
+    }
+
+    /// Returns whether the key of `prefix` belonged to `who`
+    pub async fn revoke(who: &str, prefix: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let revoked = client.execute(
+            "DELETE FROM api_keys WHERE prefix = $1 AND account = $2;",
+            &[&prefix, &who]).await?;
+
+        Ok(revoked == 1)
+    }
+
+    /// Resolves `key` to its account, if it is valid and has the scope required for `method`
+    pub async fn authenticate(key: &str, method: &Method, client: &Client) -> Result<Option<String>, Box<dyn Error>> {
+        let (prefix, secret) = match parse(key) {
+            Some(parsed) => parsed,
+            None => return Ok(None)
+        };
+
+        let row = match client.query_opt(r#"
+            SELECT account, hash, scopes FROM api_keys
+            WHERE prefix = $1 AND (expires_at IS NULL OR expires_at > NOW());
+            "#,
+            &[&prefix]).await? {
+            Some(row) => row,
+            None => return Ok(None)
+        };
+
+        if !constant_time_eq(row.get(1), &Sha256::hash(secret)) {
+            return Ok(None);
+        }
+
+        let scopes: Option<Vec<String>> = row.get(2);
+        if scopes.is_some_and(|scopes| !scopes.iter().any(|scope| scope == required_scope(method))) {
+            return Ok(None);
+        }
+
+        client.execute(
+            "UPDATE api_keys SET last_used_at = NOW() WHERE prefix = $1;",
+            &[&prefix]).await?;
+
+        Ok(Some(row.get(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("wc_abcd1234_secret"), Some(("abcd1234", "secret")));
+        assert_eq!(parse("wc_abcd1234"), None);
+        assert_eq!(parse("eyJhbGciOi.x.y"), None);
+    }
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(required_scope(&Method::GET), "read");
+        assert_eq!(required_scope(&Method::DELETE), "write");
+    }
+}
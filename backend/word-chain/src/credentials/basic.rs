@@ -14,7 +14,7 @@ impl BasicAuth {
 
         let credential = match base64::prelude::BASE64_STANDARD
             .decode(terms[1].as_bytes())
-            .map(|bytes| String::from_utf8(bytes)) {
+            .map(String::from_utf8) {
             Ok(Ok(credential)) => credential,
             _ => return None
         };
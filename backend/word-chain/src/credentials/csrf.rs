@@ -0,0 +1,81 @@
+use crate::encrypt::{constant_time_eq, Salt};
+use crate::keys::{keys, KeyPurpose, Subkey};
+use hmac::{Hmac, Mac};
+use hyper::Method;
+use sha2::Sha256;
+
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Methods which must not change state, and so need no CSRF token
+pub fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+/// Double-submit token against CSRF: it is given as a cookie readable by scripts,
+/// and has to be sent back in [`CSRF_HEADER`], which other sites can't do.
+///
+/// The token is signed for its session (refresh-token family),
+/// so that a cookie planted by another (sub)domain is not accepted either.
+pub struct CsrfToken;
+
+impl CsrfToken {
+    pub fn issue(family: &str) -> String {
+        Self::issue_with(keys().subkey(KeyPurpose::Csrf), family)
+    }
+
+    pub fn verify(token: &str, family: &str) -> bool {
+        Self::verify_with(keys().subkey(KeyPurpose::Csrf), token, family)
+    }
+
+    fn issue_with(key: &Subkey, family: &str) -> String {
+        let nonce = Salt::new().value().to_string();
+        let signature = Self::sign(key, family, &nonce);
+
+        format!("{}.{}", nonce, signature)
+    }
+
+    fn verify_with(key: &Subkey, token: &str, family: &str) -> bool {
+        match token.split_once('.') {
+            Some((nonce, signature)) => constant_time_eq(signature, &Self::sign(key, family, nonce)),
+            None => false
+        }
+    }
+
+    fn sign(key: &Subkey, family: &str, nonce: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(family.as_bytes());
+        mac.update(b".");
+        mac.update(nonce.as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::derive;
+
+    fn key() -> Subkey {
+        derive(b"master", KeyPurpose::Csrf)
+    }
+
+    #[test]
+    fn test_verify() {
+        let token = CsrfToken::issue_with(&key(), "family");
+
+        assert!(CsrfToken::verify_with(&key(), &token, "family"));
+        assert!(!CsrfToken::verify_with(&key(), &token, "another"));
+        assert!(!CsrfToken::verify_with(&derive(b"another", KeyPurpose::Csrf), &token, "family"));
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let token = CsrfToken::issue_with(&key(), "family");
+        let (_, signature) = token.split_once('.').unwrap();
+
+        assert!(!CsrfToken::verify_with(&key(), &format!("forged.{}", signature), "family"));
+        assert!(!CsrfToken::verify_with(&key(), "no-signature", "family"));
+    }
+}
@@ -1,64 +1,167 @@
-use crate::encrypt::{Aes256, Salt};
-use lazy_static::lazy_static;
+use crate::credentials::keyring::{Keyring, KEYRINGS};
+use crate::encrypt::Salt;
+use chrono::TimeDelta;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 
-struct JwtKey {
-    value: String
-}
-
-impl JwtKey {
-    fn new() -> Self {
-        let jwt_key = match std::env::var("JWT_KEY") {
-            Ok(key) => key,
-            Err(_) => panic!("JWT_KEY not initialized")
-        };
-
-        Self { value: jwt_key }
-    }
-}
-
-lazy_static! {
-    static ref JWT_KEY: JwtKey = JwtKey::new();
+/// Distinguishes tokens issued for different purposes,
+/// so that one kind of token can never be accepted in place of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JwtKind {
+    Access,
+    Refresh,
+    /// Proof of password authentication, waiting for second factor
+    Challenge,
 }
 
+/// Registered claims of RFC 7519, plus `typ` which carries [`JwtKind`]
+/// and `fam` which identifies the login session the token belongs to
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Jwt {
-    account_id: String,
-    timestamp: i64,
-    nonce: String
+    iss: String,
+    sub: String,
+    iat: i64,
+    exp: i64,
+    jti: String,
+    typ: JwtKind,
+    fam: String,
 }
 
 impl Jwt {
-    pub fn new(account: &str) -> Self {
-        let timestamp = chrono::offset::Utc::now().timestamp();
+    pub fn new(account: &str, family: &str, kind: JwtKind, lifetime: TimeDelta) -> Self {
+        Self::issue(KEYRINGS.read().unwrap().of(kind), account, family, kind, lifetime)
+    }
+
+    fn issue(keyring: &Keyring, account: &str, family: &str, kind: JwtKind, lifetime: TimeDelta) -> Self {
+        let now = chrono::offset::Utc::now();
         Self {
-            account_id: account.to_string(),
-            timestamp,
-            nonce: Salt::new().value().to_string()
+            iss: keyring.issuer().to_string(),
+            sub: account.to_string(),
+            iat: now.timestamp(),
+            exp: (now + lifetime).timestamp(),
+            jti: Salt::new().value().to_string(),
+            typ: kind,
+            fam: family.to_string(),
         }
     }
 
-    pub fn from(data: &str) -> Result<Self, Box<dyn Error>> {
-        let jwt = match Aes256::decrypt(&JWT_KEY.value, data) {
-            Ok(decrypted) => decrypted,
-            Err(error) => return Err(error)
-        };
+    /// Token `id` issued before, signed again for a client which missed it
+    pub fn reissue(account: &str, family: &str, kind: JwtKind, id: &str, expires_at: i64) -> Self {
+        Self {
+            exp: expires_at,
+            jti: id.to_string(),
+            ..Self::new(account, family, kind, TimeDelta::zero())
+        }
+    }
 
-        serde_json::from_str::<Jwt>(jwt.as_str()).map_err(|error| error.into())
+    /// Verifies signature, issuer and kind of compact JWS `data`, with the keys of `kind`.
+    /// Expiration is NOT checked here; see [`Jwt::expired`].
+    pub fn from(data: &str, kind: JwtKind) -> Result<Self, Box<dyn Error>> {
+        Self::verify(KEYRINGS.read().unwrap().of(kind), data, kind)
     }
 
-    pub fn to_string(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let raw = serde_json::to_string::<Jwt>(self)?;
+    fn verify(keyring: &Keyring, data: &str, kind: JwtKind) -> Result<Self, Box<dyn Error>> {
+        let jwt = keyring.decode::<Jwt>(data)?;
+        if jwt.typ != kind {
+            return Err(format!("expected {:?} token, but {:?} token given", kind, jwt.typ).into());
+        }
+
+        Ok(jwt)
+    }
 
-        Aes256::encrypt(&JWT_KEY.value, &raw)
+    pub fn to_string(&self) -> Result<String, Box<dyn Error>> {
+        KEYRINGS.read().unwrap().of(self.typ).encode(self)
     }
 
     pub fn account_id(&self) -> &str {
-        &self.account_id
+        &self.sub
+    }
+
+    pub fn id(&self) -> &str {
+        &self.jti
+    }
+
+    pub fn family(&self) -> &str {
+        &self.fam
+    }
+
+    pub fn issued_at(&self) -> i64 {
+        self.iat
+    }
+
+    pub fn expires_at(&self) -> i64 {
+        self.exp
     }
 
-    pub fn timestamp(&self) -> i64 {
-        self.timestamp
+    pub fn expired(&self) -> bool {
+        chrono::offset::Utc::now().timestamp() >= self.exp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::keyring::Vars;
+    use crate::keys::KeyPurpose;
+    use base64::Engine;
+
+    fn keyring() -> Keyring {
+        let vars = Vars::from([("JWT_KEY".to_string(), "test-key-which-is-long-enough-for-hs256".to_string())]);
+        Keyring::load(KeyPurpose::AccessToken, &vars).unwrap()
+    }
+
+    fn token(keyring: &Keyring, account: &str, kind: JwtKind, lifetime: TimeDelta) -> String {
+        keyring.encode(&Jwt::issue(keyring, account, "family", kind, lifetime)).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let keyring = keyring();
+        let token = token(&keyring, "alice", JwtKind::Access, TimeDelta::minutes(15));
+        let jwt = Jwt::verify(&keyring, &token, JwtKind::Access).unwrap();
+
+        assert_eq!(jwt.account_id(), "alice");
+        assert_eq!(jwt.exp - jwt.iat, 15 * 60);
+        assert!(!jwt.expired());
+    }
+
+    #[test]
+    fn test_compact_jws_header() {
+        let token = token(&keyring(), "alice", JwtKind::Access, TimeDelta::minutes(15));
+        let header = token.split('.').next().unwrap();
+        let header = base64::prelude::BASE64_URL_SAFE_NO_PAD.decode(header).unwrap();
+        let header = serde_json::from_slice::<serde_json::Value>(&header).unwrap();
+
+        assert_eq!(header["alg"], "HS256");
+        assert_eq!(header["typ"], "JWT");
+    }
+
+    #[test]
+    fn test_kind_mismatch() {
+        let keyring = keyring();
+        let token = token(&keyring, "alice", JwtKind::Refresh, TimeDelta::days(90));
+
+        assert!(Jwt::verify(&keyring, &token, JwtKind::Access).is_err());
+    }
+
+    #[test]
+    fn test_tampered() {
+        let keyring = keyring();
+        let token = token(&keyring, "alice", JwtKind::Access, TimeDelta::minutes(15));
+        let mut parts = token.split('.').map(|s| s.to_string()).collect::<Vec<String>>();
+        let claims = Jwt::issue(&keyring, "mallory", "family", JwtKind::Access, TimeDelta::minutes(15));
+        parts[1] = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+
+        assert!(Jwt::verify(&keyring, &parts.join("."), JwtKind::Access).is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let keyring = keyring();
+        let token = token(&keyring, "alice", JwtKind::Access, TimeDelta::minutes(-1));
+        let jwt = Jwt::verify(&keyring, &token, JwtKind::Access).unwrap();
+
+        assert!(jwt.expired());
     }
 }
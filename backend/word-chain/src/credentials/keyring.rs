@@ -0,0 +1,349 @@
+use crate::credentials::jwt::JwtKind;
+use crate::keys::{self, KeyPurpose};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+
+/// Environment variables which keys are loaded from
+pub type Vars = HashMap<String, String>;
+
+/// Entry of `JWT_KEY_FILE`
+#[derive(Debug, Deserialize)]
+struct KeyEntry {
+    kid: String,
+    algorithm: String,
+    /// Shared secret of HS256
+    secret: Option<String>,
+    /// PEM of EdDSA/ES256; keys without it can only verify
+    private_key: Option<String>,
+    public_key: Option<String>,
+    /// RFC 3339 timestamp since which the key is not accepted anymore
+    retires_at: Option<String>,
+}
+
+/// Format of `JWT_KEY_FILE`
+#[derive(Debug, Deserialize)]
+struct KeyFile {
+    active: String,
+    keys: Vec<KeyEntry>,
+}
+
+struct JwtKey {
+    id: String,
+    algorithm: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    retires_at: Option<DateTime<Utc>>,
+}
+
+impl JwtKey {
+    fn from(entry: KeyEntry) -> Result<Self, Box<dyn Error>> {
+        let algorithm = match entry.algorithm.as_str() {
+            "HS256" => Algorithm::HS256,
+            "EdDSA" => Algorithm::EdDSA,
+            "ES256" => Algorithm::ES256,
+            other => return Err(format!("algorithm `{}` of key `{}` is not supported", other, entry.kid).into())
+        };
+
+        let (encoding, decoding) = match (algorithm, entry.secret, entry.private_key, entry.public_key) {
+            (Algorithm::HS256, Some(secret), _, _) if secret.len() < keys::KEY_LEN =>
+                return Err(format!("secret of key `{}` must be at least {} bytes", entry.kid, keys::KEY_LEN).into()),
+            (Algorithm::HS256, Some(secret), _, _) => (
+                Some(EncodingKey::from_secret(secret.as_bytes())),
+                DecodingKey::from_secret(secret.as_bytes())),
+            (Algorithm::EdDSA, _, private_key, Some(public_key)) => (
+                private_key.map(|pem| EncodingKey::from_ed_pem(pem.as_bytes())).transpose()?,
+                DecodingKey::from_ed_pem(public_key.as_bytes())?),
+            (Algorithm::ES256, _, private_key, Some(public_key)) => (
+                private_key.map(|pem| EncodingKey::from_ec_pem(pem.as_bytes())).transpose()?,
+                DecodingKey::from_ec_pem(public_key.as_bytes())?),
+            _ => return Err(format!("key `{}` is missing its secret or public key", entry.kid).into())
+        };
+
+        let retires_at = match entry.retires_at {
+            None => None,
+            Some(retires_at) => Some(DateTime::parse_from_rfc3339(&retires_at)?.to_utc())
+        };
+
+        Ok(Self { id: entry.kid, algorithm, encoding, decoding, retires_at })
+    }
+
+    fn retired(&self) -> bool {
+        self.retires_at.map(|retires_at| retires_at <= Utc::now()).unwrap_or(false)
+    }
+}
+
+/// Set of keys, of which one signs new tokens
+/// and the others only verify tokens signed before rotation, until they retire.
+///
+/// Keys are loaded from the first available source of:
+/// - `JWT_KEY_FILE`: JSON of [`KeyFile`]
+/// - `JWT_KEYS`: comma-separated `kid:secret` pairs of HS256, with `JWT_ACTIVE_KEY`
+/// - `JWT_ALGORITHM`: single key of `JWT_KEY`, or `JWT_PRIVATE_KEY_FILE` and `JWT_PUBLIC_KEY_FILE`
+///
+/// Without any of them, an HS256 key derived from `MASTER_KEY` is used, apart for each kind of token.
+/// Keys configured explicitly are shared by every kind, which `typ` claim still tells apart.
+pub struct Keyring {
+    keys: Vec<JwtKey>,
+    active: usize,
+    issuer: String,
+}
+
+impl Keyring {
+    /// Keys of tokens of `purpose`
+    pub fn load(purpose: KeyPurpose, vars: &Vars) -> Result<Self, Box<dyn Error>> {
+        let issuer = vars.get("JWT_ISSUER").cloned().unwrap_or("word-chain".to_string());
+
+        if let Some(path) = vars.get("JWT_KEY_FILE") {
+            let json = std::fs::read_to_string(path)
+                .map_err(|e| format!("could not read `{}`: {}", path, e))?;
+
+            return Self::from_json(&json, issuer);
+        }
+
+        let (keys, active) = if let Some(keys) = vars.get("JWT_KEYS") {
+            let keys = keys.split(',')
+                .map(|pair| match pair.split_once(':') {
+                    Some((kid, secret)) => Ok(Self::hs256(kid, secret)),
+                    None => Err("JWT_KEYS must be comma-separated `kid:secret` pairs")
+                })
+                .collect::<Result<Vec<KeyEntry>, &str>>()?;
+            let active = match vars.get("JWT_ACTIVE_KEY") {
+                Some(active) => active.clone(),
+                None => keys.first().map(|key| key.kid.clone()).unwrap_or_default()
+            };
+
+            (keys, active)
+        } else {
+            let key = Self::single(purpose, vars)?;
+            let active = key.kid.clone();
+
+            (vec![key], active)
+        };
+
+        Self::from_entries(KeyFile { active, keys }, issuer)
+    }
+
+    fn single(purpose: KeyPurpose, vars: &Vars) -> Result<KeyEntry, Box<dyn Error>> {
+        let read = |name: &str| -> Result<String, Box<dyn Error>> {
+            let path = vars.get(name).ok_or_else(|| format!("{} not initialized", name))?;
+            std::fs::read_to_string(path).map_err(|e| format!("could not read `{}`: {}", path, e).into())
+        };
+
+        let algorithm = vars.get("JWT_ALGORITHM").cloned().unwrap_or("HS256".to_string());
+        if algorithm == "HS256" {
+            return match vars.get("JWT_KEY") {
+                Some(secret) => Ok(Self::hs256("default", secret)),
+                // Subkey is hex-encoded, since secrets of entries are strings
+                None => Ok(Self::hs256("master", &hex::encode(keys::init()?.subkey(purpose))))
+            };
+        }
+
+        Ok(KeyEntry {
+            kid: "default".to_string(),
+            algorithm,
+            secret: None,
+            private_key: Some(read("JWT_PRIVATE_KEY_FILE")?),
+            public_key: Some(read("JWT_PUBLIC_KEY_FILE")?),
+            retires_at: None,
+        })
+    }
+
+    fn hs256(kid: &str, secret: &str) -> KeyEntry {
+        KeyEntry {
+            kid: kid.to_string(),
+            algorithm: "HS256".to_string(),
+            secret: Some(secret.to_string()),
+            private_key: None,
+            public_key: None,
+            retires_at: None,
+        }
+    }
+
+    fn from_json(json: &str, issuer: String) -> Result<Self, Box<dyn Error>> {
+        Self::from_entries(serde_json::from_str::<KeyFile>(json)?, issuer)
+    }
+
+    fn from_entries(file: KeyFile, issuer: String) -> Result<Self, Box<dyn Error>> {
+        let keys = file.keys.into_iter()
+            .map(JwtKey::from)
+            .collect::<Result<Vec<JwtKey>, Box<dyn Error>>>()?;
+
+        let active = match keys.iter().position(|key| key.id == file.active) {
+            Some(active) => active,
+            None => return Err(format!("active key `{}` is not in keyring", file.active).into())
+        };
+        if keys[active].encoding.is_none() || keys[active].retired() {
+            return Err(format!("active key `{}` cannot sign tokens", file.active).into());
+        }
+
+        Ok(Self { keys, active, issuer })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Signs `claims` with the active key, identified by `kid` header
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Box<dyn Error>> {
+        let key = &self.keys[self.active];
+
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.id.clone());
+
+        // Active key always has encoding key; see `Keyring::from_entries`
+        jsonwebtoken::encode(&header, claims, key.encoding.as_ref().unwrap()).map_err(|e| e.into())
+    }
+
+    /// Verifies signature and issuer of `data`.
+    /// Expiration is NOT checked here, since expired access-tokens are still needed for refreshing.
+    pub fn decode<T: DeserializeOwned>(&self, data: &str) -> Result<T, Box<dyn Error>> {
+        let header = jsonwebtoken::decode_header(data)?;
+
+        // Tokens signed before key identifiers were introduced have no `kid`
+        let key = match header.kid {
+            None => &self.keys[self.active],
+            Some(kid) => match self.keys.iter().find(|key| key.id == kid) {
+                Some(key) => key,
+                None => return Err(format!("unknown key `{}`", kid).into())
+            }
+        };
+        if key.retired() {
+            return Err(format!("key `{}` is retired", key.id).into());
+        }
+
+        // Algorithm is pinned by the key, not by the header
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_exp = false;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_required_spec_claims(&["iss", "sub", "iat", "exp"]);
+
+        Ok(jsonwebtoken::decode::<T>(data, &key.decoding, &validation)?.claims)
+    }
+}
+
+/// [`Keyring`] of each [`JwtKind`]
+pub struct Keyrings {
+    access: Keyring,
+    refresh: Keyring,
+    challenge: Keyring,
+}
+
+impl Keyrings {
+    fn load(vars: &Vars) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            access: Keyring::load(KeyPurpose::AccessToken, vars)?,
+            refresh: Keyring::load(KeyPurpose::RefreshToken, vars)?,
+            challenge: Keyring::load(KeyPurpose::ChallengeToken, vars)?,
+        })
+    }
+
+    pub fn of(&self, kind: JwtKind) -> &Keyring {
+        match kind {
+            JwtKind::Access => &self.access,
+            JwtKind::Refresh => &self.refresh,
+            JwtKind::Challenge => &self.challenge,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref KEYRINGS: RwLock<Keyrings> = match Keyrings::load(&std::env::vars().collect()) {
+        Ok(keyrings) => RwLock::new(keyrings),
+        Err(e) => panic!("Failed to load JWT keys: {}", e)
+    };
+}
+
+/// Replaces keys in use, e.g. on SIGHUP, with variables of the process overridden by `.env` as it is now.
+/// The environment of the process is left untouched, since other threads may be reading it.
+/// On failure, keys in use are kept untouched.
+pub fn reload() -> Result<(), Box<dyn Error>> {
+    let mut vars: Vars = std::env::vars().collect();
+    if let Ok(dotenv) = dotenvy::dotenv_iter() {
+        for var in dotenv {
+            let (name, value) = var?;
+            vars.insert(name, value);
+        }
+    }
+
+    let keyrings = Keyrings::load(&vars)?;
+    *KEYRINGS.write().unwrap() = keyrings;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Claims {
+        iss: String,
+        sub: String,
+        iat: i64,
+        exp: i64,
+    }
+
+    fn claims() -> Claims {
+        Claims { iss: "test".to_string(), sub: "alice".to_string(), iat: 0, exp: 0 }
+    }
+
+    fn keyring(active: &str, retires_at: &str) -> Keyring {
+        let json = format!(r#"{{
+            "active": "{}",
+            "keys": [
+                {{ "kid": "old", "algorithm": "HS256", "secret": "old-secret-which-is-long-enough-for-hs256", "retires_at": "{}" }},
+                {{ "kid": "new", "algorithm": "HS256", "secret": "new-secret-which-is-long-enough-for-hs256" }}
+            ]
+        }}"#, active, retires_at);
+
+        Keyring::from_json(&json, "test".to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_rotation() {
+        let token = keyring("old", "2999-01-01T00:00:00Z").encode(&claims()).unwrap();
+        let claims = keyring("new", "2999-01-01T00:00:00Z").decode::<Claims>(&token).unwrap();
+
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(jsonwebtoken::decode_header(&token).unwrap().kid.as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn test_retired() {
+        let token = keyring("old", "2999-01-01T00:00:00Z").encode(&claims()).unwrap();
+
+        assert!(keyring("new", "2000-01-01T00:00:00Z").decode::<Claims>(&token).is_err());
+    }
+
+    #[test]
+    fn test_unknown_kid() {
+        let token = keyring("new", "2999-01-01T00:00:00Z").encode(&claims()).unwrap();
+        let other = Keyring::from_json(
+            r#"{ "active": "other", "keys": [{ "kid": "other", "algorithm": "HS256", "secret": "new-secret-which-is-long-enough-for-hs256" }] }"#,
+            "test".to_string()).unwrap();
+
+        assert!(other.decode::<Claims>(&token).is_err());
+    }
+
+    #[test]
+    fn test_retired_active_key() {
+        let json = r#"{ "active": "old", "keys": [{ "kid": "old", "algorithm": "HS256", "secret": "old-secret-which-is-long-enough-for-hs256", "retires_at": "2000-01-01T00:00:00Z" }] }"#;
+
+        assert!(Keyring::from_json(json, "test".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_short_secret() {
+        let json = r#"{ "active": "short", "keys": [{ "kid": "short", "algorithm": "HS256", "secret": "short-secret" }] }"#;
+        assert!(Keyring::from_json(json, "test".to_string()).is_err());
+
+        let vars = Vars::from([("JWT_KEYS".to_string(), "short:short-secret".to_string())]);
+        assert!(Keyring::load(KeyPurpose::AccessToken, &vars).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::audit::{Audit, AuditEvent, LoginMethod};
+use crate::credentials::session::Device;
+use crate::credentials::throttle::LoginThrottle;
+use crate::credentials::tokens::{ChallengeToken, TokenPair};
+use crate::credentials::totp::SecondFactor;
+use crate::encrypt::{Argon2id, Verification};
+use crate::routes::account::AccountRow;
+use std::error::Error;
+use std::net::IpAddr;
+use tokio_postgres::Client;
+
+/// Result of a step of [`Login`]
+pub enum Authentication {
+    /// Every factor is passed, and the session is started
+    Authorized(TokenPair),
+    /// The password is passed, but the account requires the second factor
+    Challenged(ChallengeToken),
+    /// Locked out by [`LoginThrottle`] for the seconds
+    Throttled(i64),
+    /// Wrong credentials, whatever the cause is
+    Denied,
+}
+
+/// Login by password, followed by the second factor for accounts with TOTP enabled.
+/// Cookie and token endpoints only differ in how they take credentials and return the session.
+///
+/// Both steps are throttled and audited.
+/// Failures are kept until the last step is passed, or the password alone would reset lockout of the second factor.
+pub struct Login;
+
+impl Login {
+    pub async fn password(id: &str, password: &str, addr: Option<IpAddr>, device: &Device, client: &Client) -> Result<Authentication, Box<dyn Error>> {
+        let locked = LoginThrottle::locked(id, addr, client).await?;
+        if let Some(retry_after) = locked {
+            Audit::record_login(AuditEvent::LoginThrottled, id, LoginMethod::Password, device, client).await;
+            return Ok(Authentication::Throttled(retry_after));
+        }
+
+        let account = client.query_opt(
+            "SELECT * FROM accounts WHERE id = $1",
+            &[&id]).await?.map(AccountRow::from);
+
+        // Unknown accounts cost as much as known ones, and fail the same way
+        let verification = match &account {
+            Some(account) => account.verify_password(password),
+            None => Argon2id::verify_dummy(password)
+        };
+
+        let account = match (account, verification) {
+            (Some(account), Verification::Matched) => account,
+            (Some(account), Verification::Outdated) => {
+                account.upgrade_passhash(password, client).await;
+                account
+            }
+            _ => {
+                LoginThrottle::fail(id, addr, client).await?;
+                Audit::record_login(AuditEvent::LoginFailed, id, LoginMethod::Password, device, client).await;
+                return Ok(Authentication::Denied);
+            }
+        };
+
+        if SecondFactor::enabled(account.id(), client).await? {
+            Audit::record_login(AuditEvent::LoginChallenged, account.id(), LoginMethod::Password, device, client).await;
+            return Ok(Authentication::Challenged(ChallengeToken::issue(account.id(), client).await?));
+        }
+
+        Self::authorize(account.id(), LoginMethod::Password, device, client).await
+    }
+
+    /// Second step with `code`, which uses up `challenge_token` whether it is right or not
+    pub async fn second_factor(challenge_token: &str, code: &str, addr: Option<IpAddr>, device: &Device, client: &Client) -> Result<Authentication, Box<dyn Error>> {
+        let challenge = match ChallengeToken::consume(challenge_token, client).await? {
+            Some(challenge) => challenge,
+            None => return Ok(Authentication::Denied)
+        };
+
+        // Second factors are throttled as passwords are, since challenges can be issued again and again
+        let locked = LoginThrottle::locked(challenge.who(), addr, client).await?;
+        if let Some(retry_after) = locked {
+            Audit::record_login(AuditEvent::LoginThrottled, challenge.who(), LoginMethod::Totp, device, client).await;
+            return Ok(Authentication::Throttled(retry_after));
+        }
+
+        if !SecondFactor::verify(challenge.who(), code, client).await? {
+            LoginThrottle::fail(challenge.who(), addr, client).await?;
+            Audit::record_login(AuditEvent::SecondFactorFailed, challenge.who(), LoginMethod::Totp, device, client).await;
+            return Ok(Authentication::Denied);
+        }
+
+        Self::authorize(challenge.who(), LoginMethod::Totp, device, client).await
+    }
+
+    async fn authorize(who: &str, method: LoginMethod<'_>, device: &Device, client: &Client) -> Result<Authentication, Box<dyn Error>> {
+        let pair = TokenPair::authorize(who, device, client).await?;
+        LoginThrottle::succeed(who, client).await?;
+        Audit::record_login(AuditEvent::LoginSucceeded, who, method, device, client).await;
+
+        Ok(Authentication::Authorized(pair))
+    }
+}
@@ -0,0 +1,125 @@
+use crate::credentials::tokens::{AccessToken, Authorized};
+use crate::error::ApiError;
+use crate::response::{merge_headers, ResponseBody};
+use hyper::body::Incoming;
+use hyper::{Request, Response};
+use std::error::Error;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+/// Privilege which a [`Route`](crate::route::Route) can require.
+/// Roles are sets of permissions, stored in `role_permissions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Granting and revoking roles of accounts
+    ManageRoles,
+    /// Moderating contents of the others
+    Moderate,
+    /// Reading the audit log
+    ReadAudit,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 3] = [Permission::ManageRoles, Permission::Moderate, Permission::ReadAudit];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ManageRoles => "manage_roles",
+            Permission::Moderate => "moderate",
+            Permission::ReadAudit => "read_audit",
+        }
+    }
+}
+
+/// Permission of the route a request is routed to, inserted into its extensions
+#[derive(Debug, Clone, Copy)]
+pub struct RequiredPermission(pub Permission);
+
+/// Built-in roles and their permissions, restored on every start
+pub fn builtin_roles() -> Vec<(&'static str, Vec<Permission>)> {
+    vec![
+        ("admin", Permission::ALL.to_vec()),
+        ("moderator", vec![Permission::Moderate]),
+    ]
+}
+
+/// Roles of accounts, stored in `account_roles`
+pub struct Roles;
+
+impl Roles {
+    pub async fn of(account: &str, client: &Client) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows = client.query(
+            "SELECT role FROM account_roles WHERE account = $1 ORDER BY role;",
+            &[&account]).await?;
+
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn has(account: &str, permission: Permission, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let row = client.query_opt(r#"
+            SELECT 1 FROM account_roles
+            JOIN role_permissions ON role_permissions.role = account_roles.role
+            WHERE account_roles.account = $1 AND role_permissions.permission = $2
+            LIMIT 1;
+            "#,
+            &[&account, &permission.as_str()]).await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Returns `false` if the account or the role doesn't exist, or the role is already granted
+    pub async fn grant(account: &str, role: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let granted = client.execute(r#"
+            INSERT INTO account_roles (account, role)
+            SELECT $1, name FROM roles WHERE name = $2
+            ON CONFLICT DO NOTHING;
+            "#,
+            &[&account, &role]).await;
+
+        match granted {
+            Ok(granted) => Ok(granted == 1),
+            // Unknown account violates the foreign key; any other error is not of the request
+            Err(e) if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => Ok(false),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub async fn revoke(account: &str, role: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let revoked = client.execute(
+            "DELETE FROM account_roles WHERE account = $1 AND role = $2;",
+            &[&account, &role]).await?;
+
+        Ok(revoked == 1)
+    }
+}
+
+/// Authorizes `req` for `permission` before it is routed: 401 without authorization, 403 without the permission.
+///
+/// The account is stored in extensions of `req`, so that the route doesn't refresh tokens again.
+/// Returns headers of refreshed tokens, which have to be merged into the response.
+pub async fn require(req: &mut Request<Incoming>, permission: Permission, client: &Client) -> Result<Response<ResponseBody>, Response<ResponseBody>> {
+    let (account, refreshed) = AccessToken::validate_authorization(req, client).await?;
+
+    match Roles::has(account.id(), permission, client).await {
+        Ok(true) => {},
+        Ok(false) => return Err(merge_headers(ApiError::Forbidden.into_response(), refreshed)),
+        Err(e) => return Err(merge_headers(ApiError::Internal(e).into_response(), refreshed))
+    }
+
+    req.extensions_mut().insert(Authorized(account));
+
+    Ok(refreshed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_has_all_permissions() {
+        let roles = builtin_roles();
+        let (_, admin) = roles.iter().find(|(name, _)| *name == "admin").unwrap();
+
+        assert!(Permission::ALL.iter().all(|permission| admin.contains(permission)));
+    }
+}
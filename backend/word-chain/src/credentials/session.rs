@@ -0,0 +1,128 @@
+use crate::request::client_addr;
+use chrono::{DateTime, Utc};
+use hyper::body::Incoming;
+use hyper::header::USER_AGENT;
+use hyper::Request;
+use crate::openapi::Schema;
+use serde::Serialize;
+use std::error::Error;
+use tokio_postgres::Client;
+
+/// Client which a session was started from
+pub struct Device {
+    user_agent: Option<String>,
+    address: Option<String>,
+}
+
+impl Device {
+    pub fn from(req: &Request<Incoming>) -> Self {
+        Self {
+            user_agent: req.headers().get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(256).collect()),
+            address: client_addr(req).map(|addr| addr.to_string()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new(user_agent: &str, address: &str) -> Self {
+        Self { user_agent: Some(user_agent.to_string()), address: Some(address.to_string()) }
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+}
+
+/// Logged-in device of an account, which is a refresh-token family stored in `sessions`.
+/// Revoking its family removes the session.
+#[derive(Debug, Serialize)]
+pub struct Session {
+    id: String,
+    user_agent: Option<String>,
+    address: Option<String>,
+    created_at: String,
+    last_used_at: String,
+    current: bool,
+}
+
+impl Schema for Session {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "string" },
+                "user_agent": { "type": ["string", "null"] },
+                "address": { "type": ["string", "null"] },
+                "created_at": { "type": "string" },
+                "last_used_at": { "type": "string" },
+                "current": { "type": "boolean", "description": "Whether it is the session of the request" }
+            },
+            "required": ["id", "user_agent", "address", "created_at", "last_used_at", "current"]
+        })
+    }
+}
+
+impl Session {
+    pub async fn start(family: &str, who: &str, device: &Device, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute(
+            "INSERT INTO sessions (id, account, user_agent, address) VALUES ($1, $2, $3, $4);",
+            &[&family, &who, &device.user_agent, &device.address]).await?;
+
+        Ok(())
+    }
+
+    /// Records refresh of the session
+    pub async fn touch(family: &str, device: &Device, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute(
+            "UPDATE sessions SET last_used_at = NOW(), address = $2 WHERE id = $1;",
+            &[&family, &device.address]).await?;
+
+        Ok(())
+    }
+
+    /// Sessions of `who`, marking the one of `current` family
+    pub async fn list(who: &str, current: &str, client: &Client) -> Result<Vec<Self>, Box<dyn Error>> {
+        let rows = client.query(r#"
+            SELECT id, user_agent, address, created_at, last_used_at FROM sessions
+            WHERE account = $1 AND EXISTS (
+                SELECT 1 FROM refresh_tokens
+                WHERE family = sessions.id AND NOT consumed AND NOT revoked AND expires_at > NOW()
+            )
+            ORDER BY last_used_at DESC;
+            "#,
+            &[&who]).await?;
+
+        Ok(rows.into_iter().map(|row| {
+            let id: String = row.get(0);
+
+            Self {
+                current: id == current,
+                id,
+                user_agent: row.get(1),
+                address: row.get(2),
+                created_at: row.get::<_, DateTime<Utc>>(3).to_rfc3339(),
+                last_used_at: row.get::<_, DateTime<Utc>>(4).to_rfc3339(),
+            }
+        }).collect())
+    }
+
+    /// Whether session `id` belongs to `who`
+    pub async fn owned(id: &str, who: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let row = client.query_opt(
+            "SELECT 1 FROM sessions WHERE id = $1 AND account = $2;",
+            &[&id, &who]).await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn remove(id: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute("DELETE FROM sessions WHERE id = $1;", &[&id]).await?;
+
+        Ok(())
+    }
+}
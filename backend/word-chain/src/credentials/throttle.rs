@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use std::error::Error;
+use std::net::IpAddr;
+use tokio_postgres::Client;
+
+struct ThrottleConfig {
+    /// Failures allowed for an account before it is locked
+    account_attempts: i32,
+    /// Failures allowed for a client address before it is locked
+    address_attempts: i32,
+    /// Lockout after the first failure beyond allowed ones, doubled for each further failure
+    base_delay: i64,
+    max_delay: i64,
+    /// Failures older than this are forgotten
+    window: i64,
+}
+
+impl ThrottleConfig {
+    fn new() -> Self {
+        fn var(name: &str, default: i64) -> i64 {
+            match std::env::var(name).map(|v| v.parse::<i64>()) {
+                Err(_) => default,
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => panic!("{} must be an integer: {}", name, e)
+            }
+        }
+
+        Self {
+            account_attempts: var("LOGIN_ACCOUNT_ATTEMPTS", 5) as i32,
+            address_attempts: var("LOGIN_ADDRESS_ATTEMPTS", 20) as i32,
+            base_delay: var("LOGIN_BASE_DELAY_SECS", 1),
+            max_delay: var("LOGIN_MAX_DELAY_SECS", 15 * 60),
+            window: var("LOGIN_FAILURE_WINDOW_SECS", 24 * 60 * 60),
+        }
+    }
+}
+
+lazy_static! {
+    static ref THROTTLE_CONFIG: ThrottleConfig = ThrottleConfig::new();
+}
+
+/// Seconds to lock out after `failures` consecutive failures, if any
+fn lockout(failures: i32, attempts: i32) -> Option<i64> {
+    if failures < attempts {
+        return None;
+    }
+
+    let exponent = (failures - attempts).min(32) as u32;
+    Some(THROTTLE_CONFIG.base_delay
+        .saturating_mul(1i64 << exponent)
+        .min(THROTTLE_CONFIG.max_delay))
+}
+
+/// Failure counters of login per account and per client address, stored in `login_failures`.
+/// Failures of passwords and of second factors are counted together.
+///
+/// Unknown accounts are counted too, so that lockout doesn't tell whether an account exists.
+pub struct LoginThrottle;
+
+impl LoginThrottle {
+    /// Seconds until login of `account` from `addr` is allowed again, if locked out
+    pub async fn locked(account: &str, addr: Option<IpAddr>, client: &Client) -> Result<Option<i64>, Box<dyn Error>> {
+        let row = client.query_one(r#"
+            SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - NOW()))::BIGINT FROM login_failures
+            WHERE ((kind = 'account' AND subject = $1) OR (kind = 'address' AND subject = $2))
+              AND locked_until > NOW();
+            "#,
+            &[&account, &addr.map(|addr| addr.to_string())]).await?;
+
+        Ok(row.get(0))
+    }
+
+    pub async fn fail(account: &str, addr: Option<IpAddr>, client: &Client) -> Result<(), Box<dyn Error>> {
+        Self::count("account", account, THROTTLE_CONFIG.account_attempts, client).await?;
+        if let Some(addr) = addr {
+            Self::count("address", &addr.to_string(), THROTTLE_CONFIG.address_attempts, client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Resets failures of `account`, once login has completed with every factor.
+    /// Failures of the address remain, or a valid account could be used to reset them.
+    pub async fn succeed(account: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute(
+            "DELETE FROM login_failures WHERE kind = 'account' AND subject = $1;",
+            &[&account]).await?;
+
+        Ok(())
+    }
+
+    async fn count(kind: &str, subject: &str, attempts: i32, client: &Client) -> Result<(), Box<dyn Error>> {
+        let row = client.query_one(r#"
+            INSERT INTO login_failures (kind, subject, failures, last_failure) VALUES ($1, $2, 1, NOW())
+            ON CONFLICT (kind, subject) DO UPDATE SET
+                failures = CASE
+                    WHEN login_failures.last_failure < NOW() - make_interval(secs => $3) THEN 1
+                    ELSE login_failures.failures + 1
+                END,
+                last_failure = NOW()
+            RETURNING failures;
+            "#,
+            &[&kind, &subject, &(THROTTLE_CONFIG.window as f64)]).await?;
+
+        if let Some(delay) = lockout(row.get(0), attempts) {
+            client.execute(
+                "UPDATE login_failures SET locked_until = NOW() + make_interval(secs => $3) WHERE kind = $1 AND subject = $2;",
+                &[&kind, &subject, &(delay as f64)]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_backoff() {
+        assert_eq!(lockout(4, 5), None);
+        assert_eq!(lockout(5, 5), Some(1));
+        assert_eq!(lockout(6, 5), Some(2));
+        assert_eq!(lockout(9, 5), Some(16));
+        assert_eq!(lockout(100, 5), Some(15 * 60));
+    }
+}
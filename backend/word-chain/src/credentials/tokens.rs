@@ -1,31 +1,55 @@
-use crate::credentials::jwt::Jwt;
-use crate::response::new_response;
+use crate::audit::{Audit, AuditEvent};
+use crate::credentials::api_key::{is_api_key, ApiKey};
+use crate::credentials::csrf::{is_safe, CsrfToken, CSRF_COOKIE, CSRF_HEADER};
+use crate::credentials::jwt::{Jwt, JwtKind};
+use crate::credentials::session::{Device, Session};
+use crate::encrypt::{constant_time_eq, Salt};
+use crate::error::ApiError;
+use crate::extract::cookie;
+use crate::openapi::Schema;
+use crate::response::{full, new_response, ResponseBody};
 use crate::routes::account::AccountRow;
 use chrono::TimeDelta;
-use cookie::Cookie;
-use headers::HeaderMapExt;
-use http_body_util::Full;
+use cookie::{Cookie, SameSite};
 use hyper::body::{Bytes, Incoming};
-use hyper::header::{SET_COOKIE, WWW_AUTHENTICATE};
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, SET_COOKIE};
 use hyper::{Request, Response, StatusCode};
 use lazy_static::lazy_static;
+use serde::Serialize;
 use std::error::Error;
+use std::fmt::Display;
 use tokio_postgres::Client;
 
 static ACCESS_TOKEN_EXPIRES: TimeDelta = TimeDelta::minutes(15);
 static REFRESH_TOKEN_EXPIRES: TimeDelta = TimeDelta::days(90);
+static CHALLENGE_TOKEN_EXPIRES: TimeDelta = TimeDelta::minutes(5);
+/// How long a consumed refresh token still gets its successor, when the same device presents it again.
+/// Concurrent requests of a browser carry the same refresh token, but only one of them can consume it.
+static REFRESH_TOKEN_GRACE: TimeDelta = TimeDelta::seconds(10);
+
+/// `WWW-Authenticate` of rejected Bearer tokens (RFC 6750 §3)
+const BEARER_CHALLENGE: &str = "Bearer error=\"invalid_token\"";
 
 
 struct TokenConfig {
     secure: bool,
+    same_site: SameSite,
 }
 
 impl TokenConfig {
     fn new() -> Self {
+        let same_site = match std::env::var("COOKIE_SAMESITE").as_deref() {
+            Err(_) | Ok("lax") => SameSite::Lax,
+            Ok("strict") => SameSite::Strict,
+            Ok("none") => SameSite::None,
+            Ok(v) => panic!("COOKIE_SAMESITE must be one of `strict`, `lax` or `none`: {}", v)
+        };
+
         Self {
             secure: std::env::var("COOKIE_SECURE")
                 .map(|v| v == "true")
-                .unwrap_or(true)
+                .unwrap_or(true),
+            same_site,
         }
     }
 }
@@ -36,99 +60,273 @@ lazy_static! {
 
 
 pub trait Token {
-    fn new(who: &str) -> Self;
+    fn new(who: &str, family: &str) -> Self;
     fn from_request(req: &Request<Incoming>) -> Result<Self, Box<dyn Error>>
     where
         Self: Sized;
     fn who(&self) -> &str;
+    fn family(&self) -> &str;
     fn expired(&self) -> bool;
 }
 
 pub struct AccessToken {
     token: Jwt,
+    /// Whether the token was given with `Authorization: Bearer` instead of cookie
+    bearer: bool,
 }
 
 pub struct RefreshToken {
     token: Jwt,
 }
 
-fn get_token_from(name: &str, req: &Request<Incoming>) -> Option<String> {
-    match req.headers()
-        .typed_get::<headers::Cookie>()
-        .map(|cookie| cookie.get(name).map(|v| v.to_string())) {
-        Some(Some(token)) => Some(token),
+/// Short-lived token issued after password authentication of an account with second factor.
+/// It authorizes nothing by itself, but can be exchanged once with TOTP code for a session.
+pub struct ChallengeToken {
+    token: Jwt,
+}
+
+/// Account authorized before routing, e.g. by [`crate::credentials::rbac::require`].
+/// [`AccessToken::validate_authorization`] returns it as is,
+/// since validating the same request twice would refresh the same token twice.
+#[derive(Clone)]
+pub struct Authorized(pub AccountRow);
+
+/// Result of presenting a refresh token to [`RefreshToken::consume`]
+pub enum Consumption {
+    /// The token was valid and has been used up now, for the successor
+    Consumed(RefreshToken),
+    /// The token was used up moments ago by the same device, e.g. by a concurrent request;
+    /// the successor it was used up for is given again
+    Raced(RefreshToken),
+    /// The token was already used once; its whole family is revoked now
+    Reused,
+    /// The token is unknown, revoked, or expired
+    Rejected,
+}
+
+/// Access/refresh token pair of a session, returned as cookies to browsers
+/// and as JSON (RFC 6749 §5.1) to the other clients
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    #[serde(skip)]
+    family: String,
+    token_type: &'static str,
+    access_token: String,
+    expires_in: i64,
+    refresh_token: String,
+    refresh_expires_in: i64,
+}
+
+impl Schema for TokenPair {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "token_type": { "const": "Bearer" },
+                "access_token": { "type": "string" },
+                "expires_in": { "type": "integer" },
+                "refresh_token": { "type": "string" },
+                "refresh_expires_in": { "type": "integer" }
+            },
+            "required": ["token_type", "access_token", "expires_in", "refresh_token", "refresh_expires_in"]
+        })
+    }
+}
+
+fn get_bearer_from(req: &Request<Incoming>) -> Option<String> {
+    let authorization = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+
+    match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => Some(token.trim().to_string()),
         _ => None
     }
 }
 
-fn get_elapsed(jwt: &Jwt) -> TimeDelta {
-    let timestamp = chrono::DateTime::from_timestamp(jwt.timestamp(), 0).unwrap();
-    chrono::offset::Utc::now() - timestamp
+fn token_cookie(name: &str, value: String) -> Cookie<'static> {
+    Cookie::build((name.to_string(), value))
+        .path("/")
+        .http_only(true)
+        .secure(TOKEN_CONFIG.secure)
+        .same_site(TOKEN_CONFIG.same_site)
+        .build()
+}
+
+/// HttpOnly cookie of a redirection flow, scoped to `path` and expiring after `lifetime`.
+/// It is `SameSite=Lax` regardless of `COOKIE_SAMESITE`, since it has to come back
+/// with the top-level redirection from another site.
+pub fn flow_cookie(name: &'static str, value: String, path: &'static str, lifetime: TimeDelta) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path(path)
+        .http_only(true)
+        .secure(TOKEN_CONFIG.secure)
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(lifetime.num_seconds()))
+        .build()
+}
+
+/// Unlike token cookies, scripts have to read it to send [`CSRF_HEADER`]
+fn csrf_cookie(value: String) -> Cookie<'static> {
+    Cookie::build((CSRF_COOKIE, value))
+        .path("/")
+        .secure(TOKEN_CONFIG.secure)
+        .same_site(TOKEN_CONFIG.same_site)
+        .build()
+}
+
+/// Double-submit check of cookie-authenticated requests with unsafe methods
+fn csrf_protected(req: &Request<Incoming>, family: &str) -> bool {
+    if is_safe(req.method()) {
+        return true;
+    }
+
+    let header = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+    match (header, cookie(req, CSRF_COOKIE)) {
+        (Some(header), Some(cookie)) => constant_time_eq(header, &cookie) && CsrfToken::verify(&cookie, family),
+        _ => false
+    }
+}
+
+fn internal_server_error(e: Box<dyn Error>) -> Response<ResponseBody> {
+    ApiError::Internal(e).into_response()
 }
 
 impl Token for AccessToken {
-    fn new(who: &str) -> Self {
+    fn new(who: &str, family: &str) -> Self {
         Self {
-            token: Jwt::new(who)
+            token: Jwt::new(who, family, JwtKind::Access, ACCESS_TOKEN_EXPIRES),
+            bearer: false,
         }
     }
 
+    /// Reads `Authorization: Bearer` header first, then `access_token` cookie
     fn from_request(req: &Request<Incoming>) -> Result<Self, Box<dyn Error>> {
-        let encrypted_jwt = match get_token_from("access_token", req) {
-            None => return Err("missing access-token".into()),
-            Some(token) => token
-        };
-
-        let jwt = match Jwt::from(&encrypted_jwt) {
-            Ok(jwt) => jwt,
-            Err(e) => return Err(e)
+        let (token, bearer) = match (get_bearer_from(req), cookie(req, "access_token")) {
+            (Some(token), _) => (token, true),
+            (None, Some(token)) => (token, false),
+            (None, None) => return Err("missing access-token".into())
         };
 
-        Ok(Self { token: jwt })
+        Ok(Self { token: Jwt::from(&token, JwtKind::Access)?, bearer })
     }
 
     fn who(&self) -> &str {
-        &self.token.account_id()
+        self.token.account_id()
+    }
+
+    fn family(&self) -> &str {
+        self.token.family()
     }
 
     fn expired(&self) -> bool {
-        get_elapsed(&self.token) > ACCESS_TOKEN_EXPIRES
+        self.token.expired()
     }
 }
 
 impl AccessToken {
-    pub async fn validate_authorization(req: &Request<Incoming>, client: &Client) -> Result<(AccountRow, Response<Full<Bytes>>), Response<Full<Bytes>>> {
-        fn unauthorized(msg: Option<String>) -> Response<Full<Bytes>> {
-            new_response()
-                .status(StatusCode::UNAUTHORIZED)
-                .header(WWW_AUTHENTICATE, "Cookie")
-                .body(Full::from(Bytes::from(msg.unwrap_or(String::new()))))
-                .unwrap()
+    /// Checks the revocation list, which outlives the account itself;
+    /// tokens of a deleted account must not be accepted for its successor with the same id.
+    async fn revoked(&self, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let issued_at = chrono::DateTime::from_timestamp(self.token.issued_at(), 0).unwrap();
+
+        let row = client.query_opt(r#"
+            SELECT 1 FROM revocations
+            WHERE (kind = 'family' AND subject = $1)
+               OR (kind = 'account' AND subject = $2 AND revoked_at >= $3)
+            LIMIT 1;
+            "#,
+            &[&self.family(), &self.who(), &issued_at]).await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Invalidates every token issued to `who` until now
+    pub async fn revoke_account(who: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute(r#"
+            INSERT INTO revocations (kind, subject) VALUES ('account', $1)
+            ON CONFLICT (kind, subject) DO UPDATE SET revoked_at = NOW();
+            "#,
+            &[&who]).await?;
+        client.execute(
+            "UPDATE refresh_tokens SET revoked = TRUE WHERE account = $1;",
+            &[&who]).await?;
+
+        Ok(())
+    }
+
+    /// Accepts API keys as well as sessions, for routes which bots may use.
+    /// Routes managing credentials use [`AccessToken::validate_authorization`],
+    /// so that a leaked API key can't take over the account.
+    pub async fn validate_credentials(req: &Request<Incoming>, client: &Client) -> Result<(AccountRow, Response<ResponseBody>), Response<ResponseBody>> {
+        let key = match get_bearer_from(req) {
+            Some(key) if is_api_key(&key) => key,
+            _ => return Self::validate_authorization(req, client).await
+        };
+
+        let account = match ApiKey::authenticate(&key, req.method(), client).await {
+            Ok(Some(account)) => account,
+            Ok(None) => return Err(ApiError::Unauthorized { challenge: BEARER_CHALLENGE }.into_response()),
+            Err(e) => return Err(internal_server_error(e))
+        };
+
+        match client.query_one("SELECT * FROM accounts WHERE id = $1", &[&account]).await {
+            Ok(row) => Ok((AccountRow::from(row), Response::new(full(Bytes::new())))),
+            Err(e) => Err(internal_server_error(e.into()))
+        }
+    }
+
+    /// Accepts session tokens only
+    pub async fn validate_authorization(req: &Request<Incoming>, client: &Client) -> Result<(AccountRow, Response<ResponseBody>), Response<ResponseBody>> {
+        if let Some(Authorized(account)) = req.extensions().get::<Authorized>() {
+            return Ok((account.clone(), Response::new(full(Bytes::new()))));
         }
 
-        let access_token = match AccessToken::from_request(&req) {
+        let challenge = if get_bearer_from(req).is_some() { BEARER_CHALLENGE } else { "Cookie" };
+        // Why it is unauthorized is logged, but never told to clients
+        let unauthorized = |reason: Option<&dyn Display>| {
+            if let Some(reason) = reason {
+                eprintln!("Unauthorized request to `{}`: {}", req.uri().path(), reason);
+            }
+
+            ApiError::Unauthorized { challenge }.into_response()
+        };
+
+        let access_token = match AccessToken::from_request(req) {
             Ok(access_token) => access_token,
-            Err(e) => return Err(unauthorized(Some(e.to_string())))
+            Err(e) => return Err(unauthorized(Some(&e)))
         };
 
 
-        let refresh;
-        if access_token.expired() {
+        match access_token.revoked(client).await {
+            Ok(false) => {},
+            Ok(true) => return Err(unauthorized(Some(&"revoked access-token"))),
+            Err(e) => return Err(internal_server_error(e))
+        }
+
+        if !access_token.bearer && !csrf_protected(req, access_token.family()) {
+            return Err(ApiError::InvalidCsrfToken.into_response());
+        }
+
+        let refresh_token = if access_token.expired() {
+            // Bearer clients have to refresh tokens by themselves, with `TokenPair::refresh`
+            if access_token.bearer {
+                return Err(unauthorized(None));
+            }
+
             // Automatically refresh tokens
-            let refresh_token = match RefreshToken::from_request(&req) {
+            let refresh_token = match RefreshToken::from_request(req) {
                 Ok(refresh_token) => refresh_token,
-                Err(e) => return Err(unauthorized(Some(e.to_string())))
+                Err(e) => return Err(unauthorized(Some(&e)))
             };
-            if refresh_token.expired() || access_token.who() != refresh_token.who() {
+            if refresh_token.expired()
+                || access_token.who() != refresh_token.who()
+                || access_token.family() != refresh_token.family() {
                 return Err(unauthorized(None));
             }
 
-            // TODO: Refresh token should be used only once
-
-            refresh = true;
+            Some(refresh_token)
         } else {
-            refresh = false;
-        }
+            None
+        };
 
         let account = match client.query_one(
             "SELECT * FROM accounts WHERE id = $1",
@@ -140,111 +338,360 @@ impl AccessToken {
             return Err(unauthorized(None));
         };
 
-        let mut response = Response::new(Full::from(Bytes::new()));
-        if refresh {
-            let new_refresh_token = match RefreshToken::new(account.id()).token.to_string() {
-                Ok(refresh_token) => refresh_token,
-                Err(e) => return Err(new_response()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Full::from(Bytes::from(e.to_string())))
-                    .unwrap())
-            };
+        let response = match refresh_token {
+            None => Response::new(full(Bytes::new())),
+            Some(refresh_token) => {
+                let successor = match refresh_token.consume(&Device::from(req), client).await {
+                    Ok(Consumption::Consumed(successor) | Consumption::Raced(successor)) => successor,
+                    Ok(_) => return Err(unauthorized(None)),
+                    Err(e) => return Err(internal_server_error(e))
+                };
+
+                match TokenPair::of(successor) {
+                    Ok(pair) => pair.into_cookies(),
+                    Err(e) => return Err(internal_server_error(e))
+                }
+            }
+        };
 
-            let new_access_token = match AccessToken::new(account.id()).token.to_string() {
-                Ok(access_token) => access_token,
-                Err(e) => return Err(new_response()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Full::from(Bytes::from(e.to_string())))
-                    .unwrap())
-            };
+        Ok((account, response))
+    }
 
-            response.headers_mut().append(
-                SET_COOKIE,
-                Cookie::build(("refresh_token", new_refresh_token))
-                    .http_only(true)
-                    .secure(TOKEN_CONFIG.secure)
-                    .to_string()
-                    .parse()
-                    .unwrap());
-            response.headers_mut().append(
-                SET_COOKIE,
-                Cookie::build(("access_token", new_access_token))
-                    .http_only(true)
-                    .secure(TOKEN_CONFIG.secure)
-                    .to_string()
-                    .parse()
-                    .unwrap());
+    /// Starts a new session (refresh-token family) for `who`
+    pub async fn authorize(who: &str, device: &Device, client: &Client) -> Result<Response<ResponseBody>, Response<ResponseBody>> {
+        match TokenPair::authorize(who, device, client).await {
+            Ok(pair) => Ok(pair.into_cookies()),
+            Err(e) => Err(internal_server_error(e))
         }
-
-        Ok((account, response))
     }
 
-    pub async fn authorize(who: &str) -> Result<Response<Full<Bytes>>, Response<Full<Bytes>>> {
-        let new_refresh_token = match RefreshToken::new(who).token.to_string() {
-            Ok(refresh_token) => refresh_token,
-            Err(e) => return Err(new_response()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::from(Bytes::from(e.to_string())))
-                .unwrap())
+    /// Revokes the session of the request, and clears token cookies.
+    /// Tokens which cannot be verified are just cleared.
+    pub async fn deauthorize(req: &Request<Incoming>, client: &Client) -> Result<Response<ResponseBody>, Response<ResponseBody>> {
+        let session = match (AccessToken::from_request(req), RefreshToken::from_request(req)) {
+            (Ok(access_token), _) => Some((access_token.who().to_string(), access_token.family().to_string(), access_token.bearer)),
+            (_, Ok(refresh_token)) => Some((refresh_token.who().to_string(), refresh_token.family().to_string(), false)),
+            _ => None
         };
 
-        let new_access_token = match AccessToken::new(who).token.to_string() {
-            Ok(access_token) => access_token,
-            Err(e) => return Err(new_response()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::from(Bytes::from(e.to_string())))
-                .unwrap())
+        if let Some((who, family, bearer)) = session {
+            if !bearer && !csrf_protected(req, &family) {
+                return Err(ApiError::InvalidCsrfToken.into_response());
+            }
+
+            if let Err(e) = RefreshToken::revoke_family(&family, client).await {
+                return Err(internal_server_error(e));
+            }
+            Audit::record(AuditEvent::Logout, Some(&who), &Device::from(req), Some(&family), client).await;
+        }
+
+        let mut response = Response::new(full(Bytes::new()));
+        for mut cookie in [
+            token_cookie("refresh_token", String::new()),
+            token_cookie("access_token", String::new()),
+            csrf_cookie(String::new()),
+        ] {
+            cookie.make_removal();
+
+            response.headers_mut().append(SET_COOKIE, cookie.to_string().parse().unwrap());
+        }
+
+        Ok(response)
+    }
+}
+
+impl TokenPair {
+    /// Starts a new session (refresh-token family) for `who`
+    pub async fn authorize(who: &str, device: &Device, client: &Client) -> Result<Self, Box<dyn Error>> {
+        let family = Salt::new();
+        Session::start(family.value(), who, device, client).await?;
+
+        Self::issue(who, family.value(), client).await
+    }
+
+    /// Exchanges `refresh_token` for a new pair of the same session
+    pub async fn refresh(refresh_token: &str, device: &Device, client: &Client) -> Result<Option<Self>, Box<dyn Error>> {
+        let refresh_token = match Jwt::from(refresh_token, JwtKind::Refresh) {
+            Ok(token) => RefreshToken { token },
+            Err(_) => return Ok(None)
         };
+        if refresh_token.expired() {
+            return Ok(None);
+        }
+
+        match refresh_token.consume(device, client).await? {
+            Consumption::Consumed(successor) | Consumption::Raced(successor) => Ok(Some(Self::of(successor)?)),
+            _ => Ok(None)
+        }
+    }
+
+    async fn issue(who: &str, family: &str, client: &Client) -> Result<Self, Box<dyn Error>> {
+        let refresh_token = RefreshToken::new(who, family);
+        refresh_token.store(client).await?;
+
+        Self::of(refresh_token)
+    }
+
+    /// Pair of `refresh_token`, which is stored already, and a new access token
+    fn of(refresh_token: RefreshToken) -> Result<Self, Box<dyn Error>> {
+        let (who, family) = (refresh_token.who(), refresh_token.family());
+
+        Ok(Self {
+            family: family.to_string(),
+            token_type: "Bearer",
+            access_token: AccessToken::new(who, family).token.to_string()?,
+            expires_in: ACCESS_TOKEN_EXPIRES.num_seconds(),
+            refresh_token: refresh_token.token.to_string()?,
+            refresh_expires_in: REFRESH_TOKEN_EXPIRES.num_seconds(),
+        })
+    }
 
-        let mut response = Response::new(Full::from(Bytes::new()));
+    pub fn into_cookies(self) -> Response<ResponseBody> {
+        let mut response = Response::new(full(Bytes::new()));
 
         response.headers_mut().append(
             SET_COOKIE,
-            Cookie::build(("refresh_token", new_refresh_token))
-                .http_only(true)
-                .secure(TOKEN_CONFIG.secure)
-                .to_string()
-                .parse()
-                .unwrap());
+            token_cookie("refresh_token", self.refresh_token).to_string().parse().unwrap());
         response.headers_mut().append(
             SET_COOKIE,
-            Cookie::build(("access_token", new_access_token))
-                .http_only(true)
-                .secure(TOKEN_CONFIG.secure)
-                .to_string()
-                .parse()
-                .unwrap());
+            token_cookie("access_token", self.access_token).to_string().parse().unwrap());
+        response.headers_mut().append(
+            SET_COOKIE,
+            csrf_cookie(CsrfToken::issue(&self.family)).to_string().parse().unwrap());
 
-        Ok(response)
+        response
+    }
+
+    pub fn into_json(self) -> Response<ResponseBody> {
+        new_response()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-store")
+            .body(full(Bytes::from(serde_json::to_string(&self).unwrap())))
+            .unwrap()
     }
 }
 
 impl Token for RefreshToken {
-    fn new(who: &str) -> Self {
+    fn new(who: &str, family: &str) -> Self {
         Self {
-            token: Jwt::new(who)
+            token: Jwt::new(who, family, JwtKind::Refresh, REFRESH_TOKEN_EXPIRES)
         }
     }
 
     fn from_request(req: &Request<Incoming>) -> Result<Self, Box<dyn Error>> {
-        let encrypted_jwt = match get_token_from("refresh_token", req) {
+        let token = match cookie(req, "refresh_token") {
             None => return Err("missing refresh-token".into()),
             Some(token) => token
         };
 
-        let jwt = match Jwt::from(&encrypted_jwt) {
-            Ok(jwt) => jwt,
-            Err(e) => return Err(e)
-        };
-
-        Ok(Self { token: jwt })
+        Ok(Self { token: Jwt::from(&token, JwtKind::Refresh)? })
     }
 
     fn who(&self) -> &str {
-        &self.token.account_id()
+        self.token.account_id()
+    }
+
+    fn family(&self) -> &str {
+        self.token.family()
     }
 
     fn expired(&self) -> bool {
-        get_elapsed(&self.token) > REFRESH_TOKEN_EXPIRES
+        self.token.expired()
     }
-}
\ No newline at end of file
+}
+
+impl RefreshToken {
+    async fn store(&self, client: &Client) -> Result<(), Box<dyn Error>> {
+        let expires_at = chrono::DateTime::from_timestamp(self.token.expires_at(), 0).unwrap();
+
+        client.execute(
+            "INSERT INTO refresh_tokens (id, family, account, expires_at) VALUES ($1, $2, $3, $4);",
+            &[&self.token.id(), &self.family(), &self.who(), &expires_at]).await?;
+
+        Ok(())
+    }
+
+    /// Marks this token as used by `device`, for its successor.
+    /// Presenting an already used token revokes every token of its family,
+    /// since either the legitimate client or an attacker holds a stolen copy;
+    /// unless the same device used it within [`REFRESH_TOKEN_GRACE`], which gets the same successor.
+    pub async fn consume(&self, device: &Device, client: &Client) -> Result<Consumption, Box<dyn Error>> {
+        let successor = RefreshToken::new(self.who(), self.family());
+        let expires_at = chrono::DateTime::from_timestamp(successor.token.expires_at(), 0).unwrap();
+
+        // The successor is stored in the same statement, so that a concurrent request can find it
+        let consumed = client.execute(r#"
+            WITH consumed AS (
+                UPDATE refresh_tokens
+                SET consumed = TRUE, consumed_at = NOW(), consumed_address = $3, consumed_user_agent = $4, successor = $5
+                WHERE id = $1 AND account = $2 AND NOT consumed AND NOT revoked AND expires_at > NOW()
+                RETURNING family, account
+            )
+            INSERT INTO refresh_tokens (id, family, account, expires_at)
+            SELECT $5, family, account, $6 FROM consumed;
+            "#,
+            &[&self.token.id(), &self.who(), &device.address(), &device.user_agent(), &successor.token.id(), &expires_at]).await?;
+        if consumed == 1 {
+            Session::touch(self.family(), device, client).await?;
+            Audit::record(AuditEvent::TokenRefreshed, Some(self.who()), device, Some(self.family()), client).await;
+
+            return Ok(Consumption::Consumed(successor));
+        }
+
+        let since = chrono::offset::Utc::now() - REFRESH_TOKEN_GRACE;
+        let raced = client.query_opt(r#"
+            SELECT successor.id, successor.expires_at FROM refresh_tokens AS token
+            JOIN refresh_tokens AS successor ON successor.id = token.successor
+            WHERE token.id = $1 AND token.account = $2 AND token.consumed_at > $3
+              AND token.consumed_address IS NOT DISTINCT FROM $4
+              AND token.consumed_user_agent IS NOT DISTINCT FROM $5
+              AND NOT successor.consumed AND NOT successor.revoked;
+            "#,
+            &[&self.token.id(), &self.who(), &since, &device.address(), &device.user_agent()]).await?;
+        if let Some(row) = raced {
+            let expires_at: chrono::DateTime<chrono::Utc> = row.get(1);
+            let token = Jwt::reissue(self.who(), self.family(), JwtKind::Refresh, row.get(0), expires_at.timestamp());
+
+            return Ok(Consumption::Raced(RefreshToken { token }));
+        }
+
+        let reused = client.query_opt(
+            "SELECT family FROM refresh_tokens WHERE id = $1 AND account = $2 AND consumed;",
+            &[&self.token.id(), &self.who()]).await?;
+        if reused.is_none() {
+            return Ok(Consumption::Rejected);
+        }
+
+        Self::revoke_family(self.family(), client).await?;
+        eprintln!(
+            "Reuse of refresh-token detected: family `{}` of account `{}` has been revoked",
+            self.family(), self.who());
+        Audit::record(AuditEvent::RefreshTokenReused, Some(self.who()), device, Some(self.family()), client).await;
+
+        Ok(Consumption::Reused)
+    }
+
+    pub async fn revoke_family(family: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute(r#"
+            INSERT INTO revocations (kind, subject) VALUES ('family', $1)
+            ON CONFLICT (kind, subject) DO NOTHING;
+            "#,
+            &[&family]).await?;
+        client.execute(
+            "UPDATE refresh_tokens SET revoked = TRUE WHERE family = $1;",
+            &[&family]).await?;
+        Session::remove(family, client).await?;
+
+        Ok(())
+    }
+}
+
+impl ChallengeToken {
+    /// Issues a challenge of `who`, stored in `challenges` until it is consumed or expires
+    pub async fn issue(who: &str, client: &Client) -> Result<Self, Box<dyn Error>> {
+        let token = Jwt::new(who, "", JwtKind::Challenge, CHALLENGE_TOKEN_EXPIRES);
+        let expires_at = chrono::DateTime::from_timestamp(token.expires_at(), 0).unwrap();
+
+        client.execute("DELETE FROM challenges WHERE expires_at < NOW();", &[]).await?;
+        client.execute(
+            "INSERT INTO challenges (id, account, expires_at) VALUES ($1, $2, $3);",
+            &[&token.id(), &token.account_id(), &expires_at]).await?;
+
+        Ok(Self { token })
+    }
+
+    /// Verifies `data` and uses it up, whether the second factor given with it is right or not,
+    /// so that each challenge allows a single guess.
+    /// Returns `None` if it is invalid, expired or already used.
+    pub async fn consume(data: &str, client: &Client) -> Result<Option<Self>, Box<dyn Error>> {
+        let token = match Jwt::from(data, JwtKind::Challenge) {
+            Ok(token) if !token.expired() => token,
+            _ => return Ok(None)
+        };
+
+        let consumed = client.execute(
+            "DELETE FROM challenges WHERE id = $1 AND account = $2 AND expires_at > NOW();",
+            &[&token.id(), &token.account_id()]).await?;
+
+        Ok(if consumed == 1 { Some(Self { token }) } else { None })
+    }
+
+    pub fn who(&self) -> &str {
+        self.token.account_id()
+    }
+
+    /// Schema of [`ChallengeToken::into_json`]
+    pub fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": { "challenge_token": { "type": "string" }, "expires_in": { "type": "integer" } },
+            "required": ["challenge_token", "expires_in"]
+        })
+    }
+
+    /// Response of the first step of login, which has to be followed by the second factor
+    pub fn into_json(self) -> Result<Response<ResponseBody>, Box<dyn Error>> {
+        let json = serde_json::json!({
+            "challenge_token": self.token.to_string()?,
+            "expires_in": CHALLENGE_TOKEN_EXPIRES.num_seconds(),
+        });
+
+        Ok(new_response()
+            .status(StatusCode::ACCEPTED)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-store")
+            .body(full(Bytes::from(json.to_string())))
+            .unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::Route;
+    use crate::routes::account::AccountRoute;
+    use crate::routes::login::LoginRoute;
+    use std::sync::Arc;
+
+    /// Connection to `TEST_DATABASE`, with the tables of tokens; tests using it are skipped without it.
+    /// Tokens are signed with the keys of the environment, as on start.
+    async fn database() -> Option<Arc<Client>> {
+        let url = std::env::var("TEST_DATABASE").ok()?;
+        let (client, conn) = tokio_postgres::connect(&url, tokio_postgres::NoTls).await.unwrap();
+        tokio::spawn(conn);
+
+        let client = Arc::new(client);
+        AccountRoute::new(client.clone()).up().await.unwrap();
+        LoginRoute::new(client.clone()).up().await.unwrap();
+
+        Some(client)
+    }
+
+    fn refresh_token_id(pair: &TokenPair) -> String {
+        Jwt::from(&pair.refresh_token, JwtKind::Refresh).unwrap().id().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refresh() {
+        let (Some(client), Some(another)) = (database().await, database().await) else { return };
+        let who = format!("test-{}", Salt::new().value());
+        client.execute("INSERT INTO accounts (id) VALUES ($1);", &[&who]).await.unwrap();
+
+        let device = Device::new("browser", "127.0.0.1");
+        let pair = TokenPair::authorize(&who, &device, &client).await.unwrap();
+
+        // Two requests of a browser with the same cookie, on their own connections
+        let (first, second) = tokio::join!(
+            TokenPair::refresh(&pair.refresh_token, &device, &client),
+            TokenPair::refresh(&pair.refresh_token, &device, &another));
+        let (first, second) = (first.unwrap().unwrap(), second.unwrap().unwrap());
+        assert_eq!(refresh_token_id(&first), refresh_token_id(&second));
+
+        // Another device is never given the successor, and its reuse revokes the family
+        let stranger = Device::new("stranger", "192.0.2.1");
+        assert!(TokenPair::refresh(&pair.refresh_token, &stranger, &client).await.unwrap().is_none());
+        assert!(TokenPair::refresh(&first.refresh_token, &device, &client).await.unwrap().is_none());
+
+        client.execute("DELETE FROM accounts WHERE id = $1;", &[&who]).await.unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use crate::encrypt::Aes256;
+use crate::keys::{keys, KeyPurpose};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+use sha2::Sha256;
+use std::error::Error;
+use tokio_postgres::Client;
+
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+/// Symbols of recovery codes, 32 so that each is 5 bits; lookalikes `0`, `1`, `l` and `o` are left out
+const RECOVERY_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+/// Associated data of encrypted secrets
+const SECRET_PURPOSE: &str = "totp-secret";
+
+struct TotpConfig {
+    issuer: String,
+}
+
+impl TotpConfig {
+    fn new() -> Self {
+        Self {
+            issuer: std::env::var("TOTP_ISSUER").unwrap_or("word-chain".to_string()),
+        }
+    }
+}
+
+lazy_static! {
+    static ref TOTP_CONFIG: TotpConfig = TotpConfig::new();
+}
+
+/// Time-based one-time password of RFC 6238, with HMAC-SHA1, 6 digits and 30 seconds period,
+/// which authenticator apps support by default
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        Self { secret }
+    }
+
+    pub fn from_base32(secret: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self { secret: BASE32_NOPAD.decode(secret.as_bytes())? })
+    }
+
+    pub fn base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// Key URI to be rendered as QR code for authenticator apps
+    pub fn uri(&self, account: &str) -> String {
+        let issuer = utf8_percent_encode(&TOTP_CONFIG.issuer, NON_ALPHANUMERIC).to_string();
+        let account = utf8_percent_encode(account, NON_ALPHANUMERIC).to_string();
+
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer, account, self.base32(), issuer, DIGITS, PERIOD)
+    }
+
+    fn code_at(&self, step: i64) -> u32 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).unwrap();
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation of RFC 4226 §5.3
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+
+        binary % 10u32.pow(DIGITS)
+    }
+
+    /// Finds the time step matched with `code`, allowing one step of clock drift.
+    /// Steps not after `last_step` are rejected to prevent replay.
+    pub fn verify(&self, code: &str, timestamp: i64, last_step: i64) -> Option<i64> {
+        if code.len() != DIGITS as usize {
+            return None;
+        }
+        let code = code.parse::<u32>().ok()?;
+
+        let current = timestamp / PERIOD;
+        (current - 1..=current + 1)
+            .filter(|step| *step > last_step)
+            .find(|step| self.code_at(*step) == code)
+    }
+}
+
+/// Recovery code drawn uniformly from [`RECOVERY_ALPHABET`]
+fn recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_LEN)
+        .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// HMAC of a recovery code, so that stored hashes can't be brute-forced without the key
+fn recovery_hash(code: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(keys().subkey(KeyPurpose::RecoveryCode)).unwrap();
+    mac.update(code.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// TOTP enrollment and recovery codes of an account, stored in `totp` and `totp_recovery_codes`
+pub struct SecondFactor;
+
+impl SecondFactor {
+    /// Whether login of `account` requires second factor
+    pub async fn enabled(account: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let row = client.query_opt(
+            "SELECT 1 FROM totp WHERE account = $1 AND confirmed;",
+            &[&account]).await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Starts (or restarts) enrollment with a new secret.
+    /// Returns `None` if TOTP is already enabled.
+    pub async fn enroll(account: &str, client: &Client) -> Result<Option<Totp>, Box<dyn Error>> {
+        let totp = Totp::generate();
+        let secret = Aes256::encrypt(keys().subkey(KeyPurpose::Encryption), &totp.base32(), SECRET_PURPOSE)?;
+
+        let enrolled = client.execute(r#"
+            INSERT INTO totp (account, secret) VALUES ($1, $2)
+            ON CONFLICT (account) DO UPDATE SET secret = $2, last_step = 0 WHERE NOT totp.confirmed;
+            "#,
+            &[&account, &secret]).await?;
+
+        Ok(if enrolled == 1 { Some(totp) } else { None })
+    }
+
+    /// Enables TOTP if `code` is valid for the pending enrollment.
+    /// Returns recovery codes, which are never shown again.
+    pub async fn confirm(account: &str, code: &str, client: &Client) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        if !Self::verify_totp(account, code, false, client).await? {
+            return Ok(None);
+        }
+
+        client.execute(
+            "UPDATE totp SET confirmed = TRUE WHERE account = $1;",
+            &[&account]).await?;
+        client.execute(
+            "DELETE FROM totp_recovery_codes WHERE account = $1;",
+            &[&account]).await?;
+
+        let mut codes = Vec::with_capacity(RECOVERY_CODES);
+        for _ in 0..RECOVERY_CODES {
+            let code = recovery_code();
+            client.execute(
+                "INSERT INTO totp_recovery_codes (account, hash) VALUES ($1, $2);",
+                &[&account, &recovery_hash(&code)]).await?;
+
+            codes.push(code);
+        }
+
+        Ok(Some(codes))
+    }
+
+    pub async fn disable(account: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+        client.execute("DELETE FROM totp_recovery_codes WHERE account = $1;", &[&account]).await?;
+        client.execute("DELETE FROM totp WHERE account = $1;", &[&account]).await?;
+
+        Ok(())
+    }
+
+    /// Verifies either a TOTP code or an unused recovery code of enabled TOTP.
+    /// Either code is accepted only once.
+    pub async fn verify(account: &str, code: &str, client: &Client) -> Result<bool, Box<dyn Error>> {
+        if Self::verify_totp(account, code, true, client).await? {
+            return Ok(true);
+        }
+
+        let recovered = client.execute(r#"
+            UPDATE totp_recovery_codes SET used = TRUE
+            WHERE account = $1 AND hash = $2 AND NOT used;
+            "#,
+            &[&account, &recovery_hash(&code.trim().to_lowercase())]).await?;
+
+        Ok(recovered == 1)
+    }
+
+    async fn verify_totp(account: &str, code: &str, confirmed: bool, client: &Client) -> Result<bool, Box<dyn Error>> {
+        let row = match client.query_opt(
+            "SELECT secret, last_step FROM totp WHERE account = $1 AND confirmed = $2;",
+            &[&account, &confirmed]).await? {
+            Some(row) => row,
+            None => return Ok(false)
+        };
+
+        let secret: &str = row.get(0);
+        let totp = Totp::from_base32(&Aes256::decrypt(&[keys().subkey(KeyPurpose::Encryption)], secret, SECRET_PURPOSE)?)?;
+
+        let step = match totp.verify(code.trim(), chrono::offset::Utc::now().timestamp(), row.get(1)) {
+            Some(step) => step,
+            None => return Ok(false)
+        };
+
+        // Guarded by `last_step`, so that concurrent requests can't use the same code twice
+        let updated = client.execute(
+            "UPDATE totp SET last_step = $2 WHERE account = $1 AND last_step < $2;",
+            &[&account, &step]).await?;
+
+        Ok(updated == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfc6238() -> Totp {
+        Totp { secret: b"12345678901234567890".to_vec() }
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // Test vectors of RFC 6238 Appendix B (SHA1), truncated to 6 digits
+        let totp = rfc6238();
+
+        assert_eq!(totp.code_at(59 / PERIOD), 287082);
+        assert_eq!(totp.code_at(1111111109 / PERIOD), 81804);
+        assert_eq!(totp.code_at(1234567890 / PERIOD), 5924);
+        assert_eq!(totp.code_at(2000000000 / PERIOD), 279037);
+    }
+
+    #[test]
+    fn test_verify_window() {
+        let totp = rfc6238();
+
+        assert_eq!(totp.verify("287082", 59, 0), Some(1));
+        assert_eq!(totp.verify("287082", 59 + PERIOD, 0), Some(1));
+        assert_eq!(totp.verify("287082", 59 + PERIOD * 2, 0), None);
+        assert_eq!(totp.verify("081804", 1111111109, 0), Some(1111111109 / PERIOD));
+    }
+
+    #[test]
+    fn test_verify_replay() {
+        assert_eq!(rfc6238().verify("287082", 59, 1), None);
+    }
+
+    #[test]
+    fn test_base32_roundtrip() {
+        let totp = Totp::generate();
+
+        assert_eq!(Totp::from_base32(&totp.base32()).unwrap().secret, totp.secret);
+        assert_eq!(rfc6238().base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn test_recovery_code() {
+        let code = recovery_code();
+
+        assert_eq!(code.len(), RECOVERY_CODE_LEN);
+        assert!(code.bytes().all(|b| RECOVERY_ALPHABET.contains(&b)));
+    }
+}
@@ -1,7 +1,8 @@
 use std::fmt::{Debug, Display, Formatter};
 use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
-use aes_gcm::aead::{Aead, OsRng};
-
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 
 struct Error {
     message: String
@@ -58,13 +59,121 @@ impl Salt
 }
 
 
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, Version};
+use lazy_static::lazy_static;
+
+struct Argon2Config {
+    params: Params
+}
+
+impl Argon2Config {
+    fn new() -> Self {
+        fn var(name: &str, default: u32) -> u32 {
+            match std::env::var(name).map(|v| v.parse::<u32>()) {
+                Err(_) => default,
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => panic!("{} must be an unsigned integer: {}", name, e)
+            }
+        }
+
+        // Defaults are the minimum recommended by OWASP for Argon2id
+        let params = Params::new(
+            var("ARGON2_MEMORY_KIB", 19 * 1024),
+            var("ARGON2_ITERATIONS", 2),
+            var("ARGON2_PARALLELISM", 1),
+            None);
+
+        match params {
+            Ok(params) => Self { params },
+            Err(e) => panic!("Invalid argon2 parameters: {}", e)
+        }
+    }
+}
+
+lazy_static! {
+    static ref ARGON2_CONFIG: Argon2Config = Argon2Config::new();
+
+    /// Hash of no account, verified against when the account doesn't exist,
+    /// so that response time doesn't tell whether it does
+    static ref DUMMY_HASH: String = Argon2id::hash(&Salt::new().value)
+        .expect("Failed to make dummy hash");
+}
+
+/// Outcome of [`Argon2id::verify`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Mismatched,
+    Matched,
+    /// Matched, but the hash should be replaced with [`Argon2id::hash`],
+    /// because it was made with weaker parameters than current ones
+    Outdated,
+}
+
+pub struct Argon2id;
+
+impl Argon2id {
+    fn context() -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, ARGON2_CONFIG.params.clone())
+    }
+
+    /// Hashes `password` into PHC string format
+    pub fn hash(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        match Self::context().hash_password(password.as_bytes(), &salt) {
+            Ok(hash) => Ok(hash.to_string()),
+            Err(e) => Err(Box::new(Error::from(&e.to_string())))
+        }
+    }
+
+    pub fn is_phc(hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    pub fn verify(password: &str, hash: &str) -> Verification {
+        let hash = match PasswordHash::new(hash) {
+            Ok(hash) => hash,
+            Err(_) => return Verification::Mismatched
+        };
+
+        if Self::context().verify_password(password.as_bytes(), &hash).is_err() {
+            return Verification::Mismatched;
+        }
+
+        let current = &ARGON2_CONFIG.params;
+        let outdated = hash.algorithm != Algorithm::Argon2id.ident()
+            || Params::try_from(&hash)
+                .map(|params| params.m_cost() < current.m_cost()
+                    || params.t_cost() < current.t_cost()
+                    || params.p_cost() < current.p_cost())
+                .unwrap_or(true);
+
+        if outdated { Verification::Outdated } else { Verification::Matched }
+    }
+
+    /// Costs as much as [`Argon2id::verify`], but never matches
+    pub fn verify_dummy(password: &str) -> Verification {
+        Self::verify(password, &DUMMY_HASH);
+        Verification::Mismatched
+    }
+}
+
+/// Compares in time depending only on lengths, not on the position of the first difference
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    use subtle::ConstantTimeEq;
+
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+
 use sha3::Digest;
 
 pub struct Sha256;
 
 impl Sha256 {
     pub fn hash(key: &str) -> String {
-        hex::encode(&Self::hash_raw(key))
+        hex::encode(Self::hash_raw(key))
     }
 
     fn hash_raw(key: &str) -> Vec<u8> {
@@ -73,44 +182,155 @@ impl Sha256 {
 }
 
 
+const ENVELOPE_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const KEY_ID_LEN: usize = 4;
+
+/// AES-256-GCM with a versioned envelope, encoded in URL-safe base64 without padding:
+///
+/// `version (1) || key id (4) || nonce (12) || ciphertext with tag`
+///
+/// The header and `purpose` are authenticated as associated data,
+/// so that a ciphertext made for one purpose can't be used for another.
+/// The key id tells which key made a ciphertext, without revealing the key.
 pub struct Aes256;
 
 impl Aes256 {
-    pub fn encrypt(key_str: &str, plaintext: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let key_hash = Sha256::hash_raw(key_str);
-        let key = Key::<Aes256Gcm>::from_slice(&key_hash);
+    fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+    }
+
+    /// Identifies `key`; derived apart from the AES key itself
+    pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(b"word-chain key id:");
+        hasher.update(key);
+        let hash = hasher.finalize();
+
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&hash[..KEY_ID_LEN]);
+
+        id
+    }
+
+    fn associated_data(header: &[u8], purpose: &str) -> Vec<u8> {
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(purpose.as_bytes());
+
+        aad
+    }
+
+    pub fn encrypt(key: &[u8; 32], plaintext: &str, purpose: &str) -> Result<String, Box<dyn std::error::Error>> {
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
 
-        let cipher = Aes256Gcm::new(key);
+        let mut envelope = vec![ENVELOPE_VERSION];
+        envelope.extend_from_slice(&Self::key_id(key));
+        let aad = Self::associated_data(&envelope, purpose);
 
-        let ciphered_data = match cipher.encrypt(&nonce, plaintext.as_bytes()) {
+        let ciphered_data = match Self::cipher(key).encrypt(&nonce, Payload { msg: plaintext.as_bytes(), aad: &aad }) {
             Ok(v) => v,
             Err(e) => return Err(Box::new(Error::from(&e.to_string())))
         };
 
-        // combining nonce and encrypted data together for storage purpose
-        let mut encrypted_data: Vec<u8> = nonce.to_vec();
-        encrypted_data.extend_from_slice(&ciphered_data);
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&ciphered_data);
 
-        Ok(hex::encode(&encrypted_data))
+        Ok(URL_SAFE_NO_PAD.encode(&envelope))
     }
 
-    pub fn decrypt(key_str: &str, encrypted_data: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let encrypted_data = hex::decode(encrypted_data.as_bytes())?;
-
-        let key_hash = Sha256::hash_raw(key_str);
-        let key = Key::<Aes256Gcm>::from_slice(&key_hash);
+    /// Decrypts an envelope made by [`Aes256::encrypt`] for the same `purpose`,
+    /// with whichever of `keys` its key id names.
+    pub fn decrypt(keys: &[&[u8; 32]], encrypted_data: &str, purpose: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let envelope = URL_SAFE_NO_PAD.decode(encrypted_data.as_bytes())?;
+        let header_len = 1 + KEY_ID_LEN;
+        if envelope.len() < header_len + NONCE_LEN {
+            return Err(Box::new(Error::from("truncated ciphertext")));
+        }
 
-        let (nonce_arr, ciphered_data) = encrypted_data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_arr);
+        let (header, rest) = envelope.split_at(header_len);
+        if header[0] != ENVELOPE_VERSION {
+            return Err(Box::new(Error::from("unsupported ciphertext version")));
+        }
+        let key = match keys.iter().find(|key| header[1..] == Self::key_id(key)) {
+            Some(key) => key,
+            None => return Err(Box::new(Error::from("ciphertext of unknown key")))
+        };
 
-        let cipher = Aes256Gcm::new(key);
+        let (nonce_arr, ciphered_data) = rest.split_at(NONCE_LEN);
+        let aad = Self::associated_data(header, purpose);
 
-        let plaintext = match cipher.decrypt(nonce, ciphered_data) {
+        let plaintext = match Self::cipher(key).decrypt(Nonce::from_slice(nonce_arr), Payload { msg: ciphered_data, aad: &aad }) {
             Ok(v) => v,
             Err(e) => return Err(Box::new(Error::from(&e.to_string())))
         };
 
         String::from_utf8(plaintext).map_err(|e| e.into())
     }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argon2id_roundtrip() {
+        let hash = Argon2id::hash("password").unwrap();
+
+        assert!(Argon2id::is_phc(&hash));
+        assert_eq!(Argon2id::verify("password", &hash), Verification::Matched);
+        assert_eq!(Argon2id::verify("Password", &hash), Verification::Mismatched);
+    }
+
+    #[test]
+    fn test_argon2id_outdated() {
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(1024, 1, 1, None).unwrap())
+            .hash_password(b"password", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+
+        assert_eq!(Argon2id::verify("password", &weak), Verification::Outdated);
+    }
+
+    const KEY: [u8; 32] = [7u8; 32];
+    const ANOTHER: [u8; 32] = [9u8; 32];
+
+    #[test]
+    fn test_aes256_roundtrip() {
+        let encrypted = Aes256::encrypt(&KEY, "plaintext", "purpose").unwrap();
+
+        assert_eq!(Aes256::decrypt(&[&KEY], &encrypted, "purpose").unwrap(), "plaintext");
+    }
+
+    #[test]
+    fn test_aes256_rejects_other_purpose_and_key() {
+        let encrypted = Aes256::encrypt(&KEY, "plaintext", "access").unwrap();
+
+        assert!(Aes256::decrypt(&[&KEY], &encrypted, "refresh").is_err());
+        assert!(Aes256::decrypt(&[&ANOTHER], &encrypted, "access").is_err());
+    }
+
+    #[test]
+    fn test_aes256_rotation() {
+        let encrypted = Aes256::encrypt(&ANOTHER, "plaintext", "purpose").unwrap();
+
+        assert_eq!(Aes256::decrypt(&[&KEY, &ANOTHER], &encrypted, "purpose").unwrap(), "plaintext");
+    }
+
+    #[test]
+    fn test_aes256_rejects_malformed() {
+        let encrypted = Aes256::encrypt(&KEY, "plaintext", "purpose").unwrap();
+
+        assert!(Aes256::decrypt(&[&KEY], &encrypted[..8], "purpose").is_err());
+        assert!(Aes256::decrypt(&[&KEY], "", "purpose").is_err());
+        assert!(Aes256::decrypt(&[&KEY], "abc", "purpose").is_err());
+        assert!(Aes256::decrypt(&[&KEY], "AQ!garbage", "purpose").is_err());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("hash", "hash"));
+        assert!(!constant_time_eq("hash", "hasH"));
+        assert!(!constant_time_eq("hash", "hash "));
+    }
 }
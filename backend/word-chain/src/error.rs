@@ -0,0 +1,177 @@
+use crate::extract::FieldErrors;
+use crate::response::{full, new_response, ResponseBody};
+use hyper::body::Bytes;
+use hyper::header::{ALLOW, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::{Response, StatusCode};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Errors answered to clients as `application/problem+json` of RFC 7807,
+/// with a stable [`ApiError::code`] as an extension member.
+///
+/// Handlers may return it as their `Box<dyn Error>`; any other error is [`ApiError::Internal`].
+pub enum ApiError {
+    /// Fields of the request are malformed or invalid
+    InvalidRequest(FieldErrors),
+    /// Credentials are missing or invalid; `challenge` is sent as `WWW-Authenticate`
+    Unauthorized { challenge: &'static str },
+    Forbidden,
+    /// Cookie-authenticated request without a valid CSRF token
+    InvalidCsrfToken,
+    NotFound,
+    /// With the methods allowed instead
+    MethodNotAllowed(String),
+    /// An account of the id already exists
+    AccountExists,
+    /// TOTP is already enabled, and has to be disabled before enrolling again
+    SecondFactorEnabled,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// Seconds until the client may retry
+    RateLimited(u64),
+    Timeout,
+    /// Details are logged, but never sent to clients
+    Internal(Box<dyn Error>),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::Unauthorized { .. } => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::InvalidCsrfToken => "invalid_csrf_token",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::AccountExists => "account_exists",
+            ApiError::SecondFactorEnabled => "second_factor_enabled",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Timeout => "timeout",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden | ApiError::InvalidCsrfToken => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::AccountExists | ApiError::SecondFactorEnabled => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Human-readable explanation, safe to be sent to clients
+    fn detail(&self) -> Option<&'static str> {
+        match self {
+            ApiError::InvalidCsrfToken => Some("The request has to carry the CSRF token of the session"),
+            ApiError::AccountExists => Some("An account of the id already exists"),
+            ApiError::SecondFactorEnabled => Some("TOTP is already enabled"),
+            ApiError::Timeout => Some("The request took too long to be processed"),
+            _ => None
+        }
+    }
+
+    pub fn into_response(self) -> Response<ResponseBody> {
+        let status = self.status();
+
+        let mut problem = serde_json::json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or(""),
+            "status": status.as_u16(),
+            "code": self.code(),
+        });
+        if let Some(detail) = self.detail() {
+            problem["detail"] = detail.into();
+        }
+
+        let mut builder = new_response()
+            .status(status)
+            .header(CONTENT_TYPE, "application/problem+json");
+
+        match self {
+            ApiError::InvalidRequest(errors) => problem["errors"] = serde_json::json!(errors),
+            ApiError::Unauthorized { challenge } => builder = builder.header(WWW_AUTHENTICATE, challenge),
+            ApiError::MethodNotAllowed(allow) => builder = builder.header(ALLOW, allow),
+            ApiError::RateLimited(retry_after) => builder = builder.header(RETRY_AFTER, retry_after),
+            ApiError::Internal(e) => eprintln!("Internal error: {}", e),
+            _ => {}
+        }
+
+        builder.body(full(Bytes::from(problem.to_string()))).unwrap()
+    }
+}
+
+impl From<Box<dyn Error>> for ApiError {
+    fn from(e: Box<dyn Error>) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(e) => *e,
+            Err(e) => ApiError::Internal(e)
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Internal(e) => write!(f, "{}: {}", self.code(), e),
+            _ => write!(f, "{}", self.code())
+        }
+    }
+}
+
+impl Debug for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn problem_of(response: Response<ResponseBody>) -> serde_json::Value {
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hides_internal_details() {
+        let e: Box<dyn Error> = "relation \"accounts\" does not exist".into();
+        let response = ApiError::from(e).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let problem = problem_of(response).await;
+        assert_eq!(problem["code"], "internal");
+        assert_eq!(problem["status"], 500);
+        assert!(!problem.to_string().contains("accounts"));
+    }
+
+    #[tokio::test]
+    async fn keeps_api_errors_through_boxing() {
+        let e: Box<dyn Error> = ApiError::AccountExists.into();
+        let response = ApiError::from(e).into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(problem_of(response).await["code"], "account_exists");
+    }
+
+    #[tokio::test]
+    async fn challenges_unauthorized() {
+        let response = ApiError::Unauthorized { challenge: "Bearer error=\"invalid_token\"" }.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer error=\"invalid_token\"");
+        assert_eq!(problem_of(response).await["code"], "unauthorized");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::credentials::tokens::AccessToken;
+use crate::error::ApiError;
+use crate::request::read_body;
+use crate::response::{merge_headers, ResponseBody};
+use crate::routes::account::AccountRow;
+use headers::HeaderMapExt;
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_postgres::Client;
+
+/// Checks of a DTO beyond its types, run after it is deserialized
+pub trait Validate {
+    /// Adds an error to `errors` for each invalid field
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+#[derive(Debug, Serialize)]
+struct FieldError {
+    field: String,
+    message: String,
+}
+
+/// Errors of request fields, answered as [`ApiError::InvalidRequest`] with `errors: [{field, message}]`
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct FieldErrors {
+    errors: Vec<FieldError>,
+}
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.errors.push(FieldError { field: field.to_string(), message: message.to_string() });
+    }
+
+    /// Adds an error unless `valid`
+    pub fn check(&mut self, valid: bool, field: &str, message: &str) {
+        if !valid {
+            self.add(field, message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_response(self) -> Response<ResponseBody> {
+        ApiError::InvalidRequest(self).into_response()
+    }
+}
+
+fn validated<T: Validate>(value: T) -> Result<T, FieldErrors> {
+    let mut errors = FieldErrors::default();
+    value.validate(&mut errors);
+
+    if errors.is_empty() { Ok(value) } else { Err(errors) }
+}
+
+/// Failure of deserialization, reported on the field it occurred at
+fn rejected<E: std::fmt::Display>(error: serde_path_to_error::Error<E>) -> FieldErrors {
+    let message = error.inner().to_string();
+    let field = match error.path().to_string() {
+        // NOTE: Missing fields are reported on their parent, but named in the message
+        path if path == "." => message.strip_prefix("missing field `")
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field.to_string())
+            .unwrap_or_default(),
+        path => path
+    };
+
+    let mut errors = FieldErrors::default();
+    errors.add(&field, &message);
+    errors
+}
+
+fn from_urlencoded<T: DeserializeOwned + Validate>(data: &[u8]) -> Result<T, FieldErrors> {
+    let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(data));
+
+    validated(serde_path_to_error::deserialize(deserializer).map_err(rejected)?)
+}
+
+fn from_json<T: DeserializeOwned + Validate>(data: &[u8]) -> Result<T, FieldErrors> {
+    let mut deserializer = serde_json::Deserializer::from_slice(data);
+
+    validated(serde_path_to_error::deserialize(&mut deserializer).map_err(rejected)?)
+}
+
+/// Body of `req` as `T`, by its `Content-Type`: JSON, or urlencoded form if not given.
+///
+/// Other types are answered as 415.
+pub async fn body<T: DeserializeOwned + Validate>(req: Request<Incoming>) -> Result<T, Response<ResponseBody>> {
+    let content_type = req.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+
+    let json = match content_type.as_deref() {
+        None | Some("application/x-www-form-urlencoded") => false,
+        Some("application/json") => true,
+        Some(media) if media.ends_with("+json") => true,
+        Some(_) => return Err(ApiError::UnsupportedMediaType.into_response())
+    };
+
+    let body = read_body(req.into_body()).await?;
+
+    let value = if json { from_json(&body) } else { from_urlencoded(&body) };
+    value.map_err(FieldErrors::into_response)
+}
+
+/// Query string of `req` as `T`
+pub fn query<T: DeserializeOwned + Validate>(req: &Request<Incoming>) -> Result<T, FieldErrors> {
+    from_urlencoded(req.uri().query().unwrap_or("").as_bytes())
+}
+
+/// Value of cookie `name` of `req`, if given
+pub fn cookie(req: &Request<Incoming>, name: &str) -> Option<String> {
+    req.headers()
+        .typed_get::<headers::Cookie>()
+        .and_then(|cookie| cookie.get(name).map(str::to_string))
+}
+
+/// Account authorized by a request, with tokens refreshed on the way
+pub struct Authenticated {
+    account: AccountRow,
+    refreshed: Response<ResponseBody>,
+}
+
+impl Authenticated {
+    pub fn account(&self) -> &AccountRow {
+        &self.account
+    }
+
+    /// `response` with cookies of the refreshed tokens, if any
+    pub fn respond(self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        merge_headers(response, self.refreshed)
+    }
+}
+
+/// Account authorized by `req`, or the response to it otherwise: 401, or 403 without a CSRF token.
+///
+/// Sessions only, as [`AccessToken::validate_authorization`], so that API keys can't manage credentials.
+pub async fn authenticated(req: &Request<Incoming>, client: &Client) -> Result<Authenticated, Response<ResponseBody>> {
+    let (account, refreshed) = AccessToken::validate_authorization(req, client).await?;
+
+    Ok(Authenticated { account, refreshed })
+}
+
+/// [`authenticated`], accepting API keys as well, for routes which bots may use
+pub async fn credentials(req: &Request<Incoming>, client: &Client) -> Result<Authenticated, Response<ResponseBody>> {
+    let (account, refreshed) = AccessToken::validate_credentials(req, client).await?;
+
+    Ok(Authenticated { account, refreshed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Creation {
+        name: String,
+        count: Option<u32>,
+    }
+
+    impl Validate for Creation {
+        fn validate(&self, errors: &mut FieldErrors) {
+            errors.check(!self.name.is_empty(), "name", "must not be empty");
+        }
+    }
+
+    fn errors_of(errors: FieldErrors) -> serde_json::Value {
+        serde_json::to_value(errors.errors).unwrap()
+    }
+
+    #[test]
+    fn deserializes_json_and_form() {
+        let json = from_json::<Creation>(br#"{"name": "bot", "count": 3}"#).unwrap();
+        assert_eq!((json.name.as_str(), json.count), ("bot", Some(3)));
+
+        let form = from_urlencoded::<Creation>(b"name=b%C3%B6t&count=3").unwrap();
+        assert_eq!((form.name.as_str(), form.count), ("böt", Some(3)));
+    }
+
+    #[test]
+    fn reports_errors_per_field() {
+        let missing = errors_of(from_json::<Creation>(br#"{"count": 3}"#).unwrap_err());
+        assert_eq!(missing[0]["field"], "name");
+
+        let mistyped = errors_of(from_urlencoded::<Creation>(b"name=bot&count=many").unwrap_err());
+        assert_eq!(mistyped[0]["field"], "count");
+
+        let invalid = errors_of(from_json::<Creation>(br#"{"name": ""}"#).unwrap_err());
+        assert_eq!(invalid[0]["field"], "name");
+        assert_eq!(invalid[0]["message"], "must not be empty");
+    }
+}
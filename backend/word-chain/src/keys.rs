@@ -0,0 +1,198 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::OnceLock;
+
+/// Length of the master secret and of every subkey
+pub const KEY_LEN: usize = 32;
+
+pub type Subkey = [u8; KEY_LEN];
+
+/// What a subkey is used for; every purpose gets an independent key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// Encryption of secrets at rest, e.g. TOTP secrets
+    Encryption,
+    /// Signature of CSRF tokens
+    Csrf,
+    /// HS256 key of access tokens, unless JWT keys are configured explicitly
+    AccessToken,
+    /// HS256 key of refresh tokens, likewise
+    RefreshToken,
+    /// HS256 key of challenge tokens of the second factor, likewise
+    ChallengeToken,
+    /// Signature of values kept in cookies, e.g. the state of OpenID Connect login
+    Cookie,
+    /// Keyed hash of TOTP recovery codes
+    RecoveryCode,
+}
+
+impl KeyPurpose {
+    /// `info` of HKDF; changing it changes the key
+    fn info(&self) -> &'static [u8] {
+        match self {
+            KeyPurpose::Encryption => b"word-chain/v1/encryption",
+            KeyPurpose::Csrf => b"word-chain/v1/csrf",
+            KeyPurpose::AccessToken => b"word-chain/v1/access-token",
+            KeyPurpose::RefreshToken => b"word-chain/v1/refresh-token",
+            KeyPurpose::ChallengeToken => b"word-chain/v1/challenge-token",
+            KeyPurpose::Cookie => b"word-chain/v1/cookie",
+            KeyPurpose::RecoveryCode => b"word-chain/v1/recovery-code",
+        }
+    }
+}
+
+/// HKDF-SHA256 of RFC 5869
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+    let mut extract = Hmac::<Sha256>::new_from_slice(salt).unwrap();
+    extract.update(ikm);
+    let prk = extract.finalize().into_bytes();
+
+    let mut previous: Vec<u8> = vec![];
+    for (i, chunk) in okm.chunks_mut(32).enumerate() {
+        let mut expand = Hmac::<Sha256>::new_from_slice(&prk).unwrap();
+        expand.update(&previous);
+        expand.update(info);
+        expand.update(&[i as u8 + 1]);
+        previous = expand.finalize().into_bytes().to_vec();
+
+        chunk.copy_from_slice(&previous[..chunk.len()]);
+    }
+}
+
+/// Subkey of `purpose` derived from `master`
+pub fn derive(master: &[u8], purpose: KeyPurpose) -> Subkey {
+    let mut key = [0u8; KEY_LEN];
+    hkdf(b"word-chain", master, purpose.info(), &mut key);
+
+    key
+}
+
+/// Decodes `MASTER_KEY` given as hex or base64, rejecting passphrase-like secrets
+fn parse_master(secret: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let secret = secret.trim();
+    let bytes = if secret.len().is_multiple_of(2) && secret.bytes().all(|b| b.is_ascii_hexdigit()) {
+        hex::decode(secret)?
+    } else {
+        URL_SAFE_NO_PAD.decode(secret.trim_end_matches('='))
+            .or_else(|_| STANDARD.decode(secret))
+            .map_err(|_| "MASTER_KEY must be hex or base64")?
+    };
+
+    if bytes.len() < KEY_LEN {
+        return Err(format!("MASTER_KEY must be at least {} bytes, but it is {} bytes", KEY_LEN, bytes.len()).into());
+    }
+    // Random bytes are hardly repeated; this rejects e.g. all zeroes or a repeated pattern
+    if bytes.iter().collect::<HashSet<_>>().len() < KEY_LEN / 2 {
+        return Err("MASTER_KEY is not random enough; generate it with e.g. `openssl rand -hex 32`".into());
+    }
+
+    Ok(bytes)
+}
+
+/// Subkeys derived from the master secret
+pub struct Keys {
+    encryption: Subkey,
+    csrf: Subkey,
+    access_token: Subkey,
+    refresh_token: Subkey,
+    challenge_token: Subkey,
+    cookie: Subkey,
+    recovery_code: Subkey,
+}
+
+impl Keys {
+    fn load() -> Result<Self, Box<dyn Error>> {
+        let master = match std::env::var("MASTER_KEY") {
+            Ok(secret) => parse_master(&secret)?,
+            Err(_) => return Err("MASTER_KEY not initialized".into())
+        };
+
+        let derive = |purpose: KeyPurpose| derive(&master, purpose);
+
+        Ok(Self {
+            encryption: derive(KeyPurpose::Encryption),
+            csrf: derive(KeyPurpose::Csrf),
+            access_token: derive(KeyPurpose::AccessToken),
+            refresh_token: derive(KeyPurpose::RefreshToken),
+            challenge_token: derive(KeyPurpose::ChallengeToken),
+            cookie: derive(KeyPurpose::Cookie),
+            recovery_code: derive(KeyPurpose::RecoveryCode),
+        })
+    }
+
+    pub fn subkey(&self, purpose: KeyPurpose) -> &Subkey {
+        match purpose {
+            KeyPurpose::Encryption => &self.encryption,
+            KeyPurpose::Csrf => &self.csrf,
+            KeyPurpose::AccessToken => &self.access_token,
+            KeyPurpose::RefreshToken => &self.refresh_token,
+            KeyPurpose::ChallengeToken => &self.challenge_token,
+            KeyPurpose::Cookie => &self.cookie,
+            KeyPurpose::RecoveryCode => &self.recovery_code,
+        }
+    }
+
+}
+
+static KEYS: OnceLock<Keys> = OnceLock::new();
+
+/// Loads and validates keys; called on start, so that bad keys stop the server before serving
+pub fn init() -> Result<&'static Keys, Box<dyn Error>> {
+    if let Some(keys) = KEYS.get() {
+        return Ok(keys);
+    }
+
+    let keys = Keys::load()?;
+    Ok(KEYS.get_or_init(|| keys))
+}
+
+pub fn keys() -> &'static Keys {
+    init().unwrap_or_else(|e| panic!("Failed to load keys: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hkdf_rfc5869() {
+        // Test case 1 of RFC 5869 Appendix A
+        let ikm = [0x0bu8; 22];
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+
+        let mut okm = [0u8; 42];
+        hkdf(&salt, &ikm, &info, &mut okm);
+
+        assert_eq!(
+            hex::encode(okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+    }
+
+    #[test]
+    fn test_parse_master() {
+        let hex = "7c1f4a3be2d98f60a1b5c3e7d2f4a6b8c0e1f3a5b7d9e2c4f6a8b0d1e3f5a7c9";
+
+        assert_eq!(parse_master(hex).unwrap().len(), 32);
+        assert_eq!(parse_master(&URL_SAFE_NO_PAD.encode(hex::decode(hex).unwrap())).unwrap().len(), 32);
+        assert!(parse_master("correct horse battery staple").is_err());
+        assert!(parse_master(&"00".repeat(32)).is_err());
+        assert!(parse_master(&hex[..32]).is_err());
+    }
+
+    #[test]
+    fn test_subkeys_are_independent() {
+        let purposes = [
+            KeyPurpose::Encryption, KeyPurpose::Csrf, KeyPurpose::AccessToken,
+            KeyPurpose::RefreshToken, KeyPurpose::ChallengeToken, KeyPurpose::Cookie,
+            KeyPurpose::RecoveryCode,
+        ];
+        let subkeys = purposes.iter().map(|purpose| derive(b"master", *purpose)).collect::<HashSet<_>>();
+
+        assert_eq!(subkeys.len(), purposes.len());
+    }
+}
@@ -1,92 +1,92 @@
 mod response;
 mod route;
 mod encrypt;
+mod error;
+mod extract;
 mod routes;
 mod request;
 mod credentials;
+mod audit;
+mod keys;
+mod middleware;
+mod openapi;
 
-use crate::response::{new_response, set_response_option, ResponseOption};
+use crate::error::ApiError;
 use routes::root::RootRoute;
-use crate::route::{down_all, match_route, up_all};
-use http_body_util::Full;
-use hyper::body::Bytes;
+use crate::credentials::keyring;
+use crate::request::PeerAddr;
+use crate::route::{down_all, up_all, RouteTable};
+use crate::response::ResponseBody;
+use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::StatusCode;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
 use std::convert::Infallible;
 use std::io::{stdout, Write};
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, OnceLock};
 use tokio::net::TcpListener;
 use tokio_postgres::{Client, NoTls};
 
 
 struct GlobalData {
-    root: Arc<RootRoute>
+    root: &'static RootRoute,
+    table: RouteTable<'static>
 }
 
 impl GlobalData {
     fn new(database: Arc<Client>) -> Self {
-        Self {
-            root: Arc::new(RootRoute::new(&database))
-        }
+        // Routes live as long as the process, so that the table can refer to them
+        let root: &'static RootRoute = Box::leak(Box::new(RootRoute::new(&database)));
+        let table = match RouteTable::new(root) {
+            Ok(table) => table,
+            Err(e) => panic!("Failed to compile routes: {}", e)
+        };
+
+        Self { root, table }
     }
 }
 
-static GLOBAL: RwLock<Option<GlobalData>> = RwLock::new(None);
-
+static GLOBAL: OnceLock<GlobalData> = OnceLock::new();
 
-async fn map(req: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-    let root_arc = GLOBAL.read().unwrap().as_ref().unwrap().root.clone();
 
-    let route = match match_route(req.uri().path(), root_arc.as_ref()) {
-        Some(route) => route,
-        None => return Ok(new_response()
-            .status(StatusCode::NOT_FOUND)
-            .body(Full::from(Bytes::new()))
-            .unwrap())
-    };
+async fn map(req: Request<Incoming>) -> Result<Response<ResponseBody>, Infallible> {
+    let global = GLOBAL.get().unwrap();
 
-    let res = match route.map(req).await {
+    // NOTE: Errors are already recovered by middleware of the root, this is the last resort
+    match global.table.dispatch(req).await {
         Ok(resp) => Ok(resp),
-        Err(e) => Ok(new_response()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Full::from(Bytes::from(e.to_string())))
-            .unwrap())
-    };
-
-    // root-route should be alive until scope has closed
-    let _ = root_arc;
-
-    res
+        Err(e) => Ok(ApiError::from(e).into_response())
+    }
 }
 
 async fn configure() {
     dotenvy::dotenv().ok();
 
+    // Keys are checked before anything else, rather than on their first use
+    if let Err(e) = keys::init() {
+        panic!("Invalid keys: {}", e);
+    }
+    lazy_static::initialize(&keyring::KEYRINGS);
+
     // Connect to postgres
     let database_url = std::env::var("DATABASE")
         .expect("environment variable `DATABASE` must be set`");
     let (client, conn) = tokio_postgres::connect(&database_url, NoTls).await
-        .expect(&format!("Could not connect to `{}`", database_url));
+        .unwrap_or_else(|_| panic!("Could not connect to `{}`", database_url));
     tokio::spawn(async move {
         if let Err(e) = conn.await {
             eprintln!("Error occurs on connection with database: {}", e);
         }
     });
 
-    set_response_option(ResponseOption::AllowCors);
-
-    GLOBAL.write().unwrap().replace(GlobalData::new(Arc::new(client)));
+    let global = GLOBAL.get_or_init(|| GlobalData::new(Arc::new(client)));
 
     // INITIALISE ALL ROUTES
-    let root_arc = GLOBAL.read().unwrap().as_ref().unwrap().root.clone();
-    if let Err(e) = up_all(root_arc.as_ref()).await {
+    if let Err(e) = up_all(global.root).await {
         panic!("Failed to initialize routes: {}", e);
     }
-    let _ = root_arc;
 }
 
 async fn shutdown() {
@@ -95,11 +95,9 @@ async fn shutdown() {
     // FINALISE ALL ROUTES
     // Critical section: If finalisation doesn't work properly,
     // It will leave permanent sub-effect on system (especially, for DATABASE)
-    let root_arc = GLOBAL.read().unwrap().as_ref().unwrap().root.clone();
-    if let Err(e) = down_all(root_arc.as_ref()).await {
-        eprintln!("Failed to initialize routes: {}", e);
+    if let Err(e) = down_all(GLOBAL.get().unwrap().root).await {
+        eprintln!("Failed to finalize routes: {}", e);
     }
-    let _ = root_arc;
 
     stdout().flush().ok();
 }
@@ -112,6 +110,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
     let listener = TcpListener::bind(addr).await?;
 
+    tokio::spawn(async {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+
+        while hangup.recv().await.is_some() {
+            match keyring::reload() {
+                Ok(()) => eprintln!("JWT keys reloaded"),
+                Err(e) => eprintln!("Failed to reload JWT keys: {}", e)
+            }
+        }
+    });
+
     let graceful = hyper_util::server::graceful::GracefulShutdown::new();
     let mut signal = std::pin::pin!(async {
         tokio::signal::ctrl_c().await
@@ -120,12 +130,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     loop {
         tokio::select! {
-            Ok((stream, _)) = listener.accept() => {
+            Ok((stream, peer)) = listener.accept() => {
                 let io = TokioIo::new(stream);
 
                 tokio::task::spawn(async move {
                     if let Err(err) = http1::Builder::new()
-                        .serve_connection(io, service_fn(map))
+                        .serve_connection(io, service_fn(move |mut req: Request<Incoming>| {
+                            req.extensions_mut().insert(PeerAddr(peer.ip()));
+                            map(req)
+                        }))
                         .await
                     {
                         eprintln!("Error serving connection: {:?}", err);
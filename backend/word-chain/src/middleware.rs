@@ -0,0 +1,6 @@
+pub mod logger;
+pub mod cors;
+pub mod timeout;
+pub mod recover;
+pub mod authorize;
+pub mod rate_limit;
\ No newline at end of file
@@ -0,0 +1,37 @@
+use crate::credentials::rbac::{self, RequiredPermission};
+use crate::response::merge_headers;
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::Request;
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Checks [`Route::permission`](crate::route::Route::permission) of the routed route,
+/// given as [`RequiredPermission`]; the authorized account is cached for the handler
+pub struct Authorize {
+    client: Arc<Client>,
+}
+
+impl Authorize {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+impl Middleware for Authorize {
+    fn handle<'a>(&'a self, mut req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            let permission = match req.extensions().get::<RequiredPermission>() {
+                Some(required) => required.0,
+                None => return next.run(req).await
+            };
+
+            let refreshed = match rbac::require(&mut req, permission, &self.client).await {
+                Ok(refreshed) => refreshed,
+                Err(e) => return Ok(e)
+            };
+
+            Ok(merge_headers(next.run(req).await?, refreshed))
+        })
+    }
+}
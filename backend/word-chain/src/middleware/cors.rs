@@ -0,0 +1,54 @@
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ALLOW, VARY,
+};
+use hyper::{Method, Request};
+
+/// CORS headers for `CORS_ALLOWED_ORIGIN`; without it, nothing is added.
+///
+/// Preflight is answered by the route table as OPTIONS, of which `Allow` is copied.
+pub struct Cors {
+    origin: Option<HeaderValue>,
+}
+
+impl Cors {
+    pub fn from_env() -> Self {
+        let origin = std::env::var("CORS_ALLOWED_ORIGIN").ok()
+            .map(|origin| HeaderValue::from_str(&origin)
+                .unwrap_or_else(|e| panic!("CORS_ALLOWED_ORIGIN is not a header value: {}", e)));
+
+        Self { origin }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+            let mut response = next.run(req).await?;
+
+            let origin = match &self.origin {
+                Some(origin) => origin.clone(),
+                None => return Ok(response)
+            };
+
+            // NOTE: Never `*`, since responses depend on cookies of the requester
+            let headers = response.headers_mut();
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+
+            if preflight {
+                if let Some(allow) = headers.get(ALLOW).cloned() {
+                    headers.insert(ACCESS_CONTROL_ALLOW_METHODS, allow);
+                    headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("Authorization, Content-Type, X-CSRF-Token"));
+                    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+                }
+            }
+
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,27 @@
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::Request;
+use std::time::Instant;
+
+/// Logs method, path, status and elapsed time of every request
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let method = req.method().clone();
+            let path = req.uri().path().to_string();
+
+            let result = next.run(req).await;
+            let elapsed = started.elapsed().as_millis();
+
+            match &result {
+                Ok(response) => println!("{} {} {} {}ms", method, path, response.status().as_u16(), elapsed),
+                Err(e) => eprintln!("{} {} failed in {}ms: {}", method, path, elapsed, e)
+            }
+
+            result
+        })
+    }
+}
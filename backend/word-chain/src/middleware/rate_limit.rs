@@ -0,0 +1,100 @@
+use crate::error::ApiError;
+use crate::request::client_addr;
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::Request;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counters are pruned of expired windows once this many addresses are tracked
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Fixed-window limit of requests per client address, kept in memory.
+///
+/// Each route subtree with this layer has its own counters.
+pub struct RateLimit {
+    requests: u32,
+    window: Duration,
+    counters: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(requests: u32, window: Duration) -> Self {
+        Self { requests, window, counters: Mutex::new(HashMap::new()) }
+    }
+
+    /// `RATE_LIMIT_REQUESTS` per `RATE_LIMIT_WINDOW_SECS`, 30 per 60 by default
+    pub fn from_env() -> Self {
+        fn var(name: &str, default: u64) -> u64 {
+            match std::env::var(name).map(|v| v.parse::<u64>()) {
+                Err(_) => default,
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => panic!("{} must be an unsigned integer: {}", name, e)
+            }
+        }
+
+        Self::new(
+            var("RATE_LIMIT_REQUESTS", 30) as u32,
+            Duration::from_secs(var("RATE_LIMIT_WINDOW_SECS", 60)))
+    }
+
+    /// Counts a request of `addr` at `now`; time until the window ends, if over the limit
+    fn hit(&self, addr: IpAddr, now: Instant) -> Option<Duration> {
+        let mut counters = self.counters.lock().unwrap();
+
+        if counters.len() >= PRUNE_THRESHOLD {
+            counters.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        }
+
+        let (started, count) = counters.entry(addr).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *count = 0;
+        }
+
+        if *count >= self.requests {
+            return Some(self.window - now.duration_since(*started));
+        }
+
+        *count += 1;
+        None
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            // NOTE: Without a client address, there is nothing to count by
+            let retry = client_addr(&req).and_then(|addr| self.hit(addr, Instant::now()));
+
+            match retry {
+                // Rounded up, so that retrying right after doesn't hit the limit again
+                Some(retry) => Ok(ApiError::RateLimited(retry.as_secs() + 1).into_response()),
+                None => next.run(req).await
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_requests_within_window() {
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+        let addr = IpAddr::from([127, 0, 0, 1]);
+        let other = IpAddr::from([127, 0, 0, 2]);
+        let now = Instant::now();
+
+        assert_eq!(limit.hit(addr, now), None);
+        assert_eq!(limit.hit(addr, now + Duration::from_secs(1)), None);
+        assert_eq!(limit.hit(addr, now + Duration::from_secs(10)), Some(Duration::from_secs(50)));
+        assert_eq!(limit.hit(other, now + Duration::from_secs(10)), None);
+
+        // A new window starts once the previous one is over
+        assert_eq!(limit.hit(addr, now + Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,18 @@
+use crate::error::ApiError;
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::Request;
+
+/// Turns errors of inner layers into their [`ApiError`] responses, so that outer layers always see a response
+pub struct Recover;
+
+impl Middleware for Recover {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            match next.run(req).await {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(ApiError::from(e).into_response())
+            }
+        })
+    }
+}
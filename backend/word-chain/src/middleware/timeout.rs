@@ -0,0 +1,33 @@
+use crate::error::ApiError;
+use crate::route::{FutureAction, Middleware, Next};
+use hyper::body::Incoming;
+use hyper::Request;
+use std::time::Duration;
+
+/// Answers 503 if inner layers don't answer in time; `REQUEST_TIMEOUT_SECS`, 30 by default
+pub struct Timeout {
+    duration: Duration,
+}
+
+impl Timeout {
+    pub fn from_env() -> Self {
+        let secs = match std::env::var("REQUEST_TIMEOUT_SECS").map(|v| v.parse::<u64>()) {
+            Err(_) => 30,
+            Ok(Ok(secs)) => secs,
+            Ok(Err(e)) => panic!("REQUEST_TIMEOUT_SECS must be an unsigned integer: {}", e)
+        };
+
+        Self { duration: Duration::from_secs(secs) }
+    }
+}
+
+impl Middleware for Timeout {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a> {
+        Box::pin(async move {
+            match tokio::time::timeout(self.duration, next.run(req)).await {
+                Ok(result) => result,
+                Err(_) => Ok(ApiError::Timeout.into_response())
+            }
+        })
+    }
+}
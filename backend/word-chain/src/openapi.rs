@@ -0,0 +1,310 @@
+use crate::route::{Pattern, Route};
+use serde_json::{json, Map, Value};
+
+/// JSON Schema of a DTO, as OpenAPI 3.1 uses them
+pub trait Schema {
+    fn schema() -> Value;
+}
+
+#[derive(Clone, Copy)]
+enum Security {
+    /// Session tokens, either cookies or bearer
+    Session,
+    /// `Authorization: Basic` with id and password
+    Basic,
+}
+
+/// Description of a handler in the OpenAPI document
+#[derive(Default)]
+pub struct Operation {
+    summary: &'static str,
+    query: Option<Value>,
+    /// Schema of JSON or urlencoded form
+    body: Option<Value>,
+    responses: Vec<(u16, &'static str, Option<Value>)>,
+    security: Option<Security>,
+}
+
+impl Operation {
+    pub fn new(summary: &'static str) -> Self {
+        Self { summary, ..Self::default() }
+    }
+
+    /// Query string as read by [`extract::query`](crate::extract::query)
+    pub fn query<T: Schema>(mut self) -> Self {
+        self.query = Some(T::schema());
+        self
+    }
+
+    /// Body as read by [`extract::body`](crate::extract::body), either JSON or urlencoded form
+    pub fn body<T: Schema>(mut self) -> Self {
+        self.body = Some(T::schema());
+        self
+    }
+
+    pub fn response(mut self, status: u16, description: &'static str) -> Self {
+        self.responses.push((status, description, None));
+        self
+    }
+
+    /// Response with JSON body of `schema`
+    pub fn json(mut self, status: u16, description: &'static str, schema: Value) -> Self {
+        self.responses.push((status, description, Some(schema)));
+        self
+    }
+
+    /// Requires session tokens, either cookies or bearer
+    pub fn authorized(mut self) -> Self {
+        self.security = Some(Security::Session);
+        self
+    }
+
+    /// Requires `Authorization: Basic` with id and password
+    pub fn basic(mut self) -> Self {
+        self.security = Some(Security::Basic);
+        self
+    }
+
+    fn to_json(&self, parameters: &[Value], permission: Option<&str>) -> Value {
+        let mut operation = Map::new();
+        if !self.summary.is_empty() {
+            operation.insert("summary".to_string(), self.summary.into());
+        }
+
+        let mut parameters = parameters.to_vec();
+        if let Some(query) = &self.query {
+            parameters.extend(query_parameters(query));
+        }
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), parameters.into());
+        }
+
+        if let Some(schema) = &self.body {
+            operation.insert("requestBody".to_string(), json!({
+                "required": true,
+                "content": {
+                    "application/json": { "schema": schema },
+                    "application/x-www-form-urlencoded": { "schema": schema }
+                }
+            }));
+        }
+
+        let mut responses = Map::new();
+        for (status, description, schema) in &self.responses {
+            let response = match schema {
+                Some(schema) => json!({ "description": description, "content": { "application/json": { "schema": schema } } }),
+                None => json!({ "description": description })
+            };
+            responses.insert(status.to_string(), response);
+        }
+        responses.insert("default".to_string(), problem_response());
+        operation.insert("responses".to_string(), responses.into());
+
+        // NOTE: Permissions are checked on the session
+        let security = match (self.security, permission) {
+            (Some(Security::Basic), _) => Some(json!([{ "basic": [] }])),
+            (Some(Security::Session), _) | (None, Some(_)) => Some(json!([{ "session": [] }, { "bearer": [] }])),
+            (None, None) => None
+        };
+        if let Some(security) = security {
+            operation.insert("security".to_string(), security);
+        }
+        if let Some(permission) = permission {
+            operation.insert("x-permission".to_string(), permission.into());
+        }
+
+        operation.into()
+    }
+}
+
+/// Query parameters of each property of an object schema
+fn query_parameters(schema: &Value) -> Vec<Value> {
+    let required = schema["required"].as_array().cloned().unwrap_or_default();
+
+    schema["properties"].as_object().into_iter().flatten()
+        .map(|(name, property)| json!({
+            "name": name,
+            "in": "query",
+            "required": required.contains(&Value::from(name.as_str())),
+            "schema": property
+        }))
+        .collect()
+}
+
+fn problem_response() -> Value {
+    json!({
+        "description": "Error",
+        "content": { "application/problem+json": { "schema": { "$ref": "#/components/schemas/Problem" } } }
+    })
+}
+
+/// Adds path items of `route` and its descendants to `paths`
+fn collect(route: &dyn Route, path: &str, parameters: &[Value], paths: &mut Map<String, Value>) {
+    let mut parameters = parameters.to_vec();
+    let path = match Pattern::of(route.name()) {
+        Pattern::Static("") => path.to_string(),
+        Pattern::Static(name) => format!("{}/{}", path, name),
+        Pattern::Param(name) => {
+            let name = name.unwrap_or("_");
+            parameters.push(json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }));
+            format!("{}/{{{}}}", path, name)
+        }
+        Pattern::CatchAll(name) => {
+            parameters.push(json!({
+                "name": name, "in": "path", "required": true, "schema": { "type": "string" },
+                "description": "Rest of the path, including `/`"
+            }));
+            format!("{}/{{{}}}", path, name)
+        }
+    };
+
+    let methods = route.methods();
+    let permission = route.permission().map(|permission| permission.as_str());
+
+    let mut item = Map::new();
+    for (method, operation) in methods.operations() {
+        let operation = match operation {
+            Some(operation) => operation.to_json(&parameters, permission),
+            None => Operation::default().to_json(&parameters, permission)
+        };
+        item.insert(method.as_str().to_ascii_lowercase(), operation);
+    }
+    if !item.is_empty() {
+        paths.insert(if path.is_empty() { "/".to_string() } else { path.clone() }, item.into());
+    }
+
+    for child in route.children() {
+        collect(child, &path, &parameters, paths);
+    }
+}
+
+/// OpenAPI 3.1 document of all routes under `root`
+pub fn document(root: &dyn Route) -> Value {
+    let mut paths = Map::new();
+    collect(root, "", &[], &mut paths);
+
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": "word-chain", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Problem": {
+                    "type": "object",
+                    "description": "RFC 7807 problem details, with a stable `code`",
+                    "properties": {
+                        "type": { "type": "string" },
+                        "title": { "type": "string" },
+                        "status": { "type": "integer" },
+                        "code": { "type": "string" },
+                        "detail": { "type": "string" },
+                        "errors": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": { "field": { "type": "string" }, "message": { "type": "string" } }
+                            }
+                        }
+                    },
+                    "required": ["type", "title", "status", "code"]
+                }
+            },
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": "access_token" },
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "basic": { "type": "http", "scheme": "basic" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::rbac::Permission;
+    use crate::route::{FutureAction, FuturePreparation, Methods};
+    use crate::response::full;
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use std::fmt::{Display, Formatter};
+
+    struct Query;
+
+    impl Schema for Query {
+        fn schema() -> Value {
+            json!({ "type": "object", "properties": { "limit": { "type": "integer" } }, "required": ["limit"] })
+        }
+    }
+
+    /// `/items/{id}`, of which only `{id}` has handlers
+    struct DocRoute {
+        name: &'static str,
+        children: Vec<DocRoute>,
+    }
+
+    impl Display for DocRoute {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "DocRoute({})", self.name)
+        }
+    }
+
+    fn ok<'a>() -> FutureAction<'a> {
+        Box::pin(async { Ok(Response::new(full(Bytes::new()))) })
+    }
+
+    impl Route for DocRoute {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn children(&self) -> Vec<&dyn Route> {
+            self.children.iter().map(|child| child as &dyn Route).collect()
+        }
+
+        fn up(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn down(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn methods(&self) -> Methods<'_> {
+            if self.name != "{id}" {
+                return Methods::new();
+            }
+
+            Methods::new()
+                .get(|_req| ok())
+                .describe(Operation::new("An item").query::<Query>().response(200, "The item"))
+                .delete(|_req| ok())
+        }
+
+        fn permission(&self) -> Option<Permission> {
+            if self.name == "{id}" { Some(Permission::Moderate) } else { None }
+        }
+    }
+
+    #[test]
+    fn documents_routes_with_handlers() {
+        let root = DocRoute { name: "", children: vec![
+            DocRoute { name: "items", children: vec![DocRoute { name: "{id}", children: vec![] }] }
+        ] };
+        let document = document(&root);
+
+        let paths = document["paths"].as_object().unwrap();
+        assert_eq!(paths.keys().collect::<Vec<_>>(), ["/items/{id}"]);
+
+        let get = &paths["/items/{id}"]["get"];
+        assert_eq!(get["summary"], "An item");
+        assert_eq!(get["parameters"][0]["name"], "id");
+        assert_eq!(get["parameters"][0]["in"], "path");
+        assert_eq!(get["parameters"][1]["name"], "limit");
+        assert_eq!(get["parameters"][1]["required"], true);
+        assert_eq!(get["x-permission"], "moderate");
+        assert!(get["responses"]["200"].is_object() && get["responses"]["default"].is_object());
+
+        // Undescribed handlers are listed too
+        assert!(paths["/items/{id}"]["delete"]["responses"]["default"].is_object());
+    }
+}
@@ -1,22 +1,36 @@
-use http_body_util::Full;
-use hyper::body::{Body, Bytes, Incoming};
-use hyper::{Response, StatusCode};
+use hyper::body::{Body, Incoming};
+use hyper::{Request, Response};
 use http_body_util::BodyExt;
-use crate::response::new_response;
+use std::net::IpAddr;
+use crate::error::ApiError;
+use crate::response::ResponseBody;
 
-pub async fn read_body(body: Incoming) -> Result<Vec<u8>, Response<Full<Bytes>>> {
+/// Address of the connected peer, inserted into extensions of every request
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub IpAddr);
+
+/// Address of the client who sent `req`.
+///
+/// If `FORWARDED_HEADER` is set (e.g. `X-Forwarded-For`), the last address of the header is used,
+/// which is the one appended by the reverse proxy in front of this server.
+pub fn client_addr(req: &Request<Incoming>) -> Option<IpAddr> {
+    if let Ok(header) = std::env::var("FORWARDED_HEADER") {
+        return req.headers().get(header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|addr| addr.trim().parse().ok());
+    }
+
+    req.extensions().get::<PeerAddr>().map(|peer| peer.0)
+}
+
+pub async fn read_body(body: Incoming) -> Result<Vec<u8>, Response<ResponseBody>> {
     if body.size_hint().upper().unwrap_or(u64::MAX) > 1024 * 64 {
-        return Err(new_response()
-            .status(StatusCode::PAYLOAD_TOO_LARGE)
-            .body(Full::from(Bytes::new()))
-            .unwrap());
+        return Err(ApiError::PayloadTooLarge.into_response());
     }
 
     match body.collect().await {
         Ok(body) => Ok(body.to_bytes().to_vec()),
-        Err(e) => Err(new_response()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Full::from(Bytes::from(e.to_string())))
-            .unwrap())
+        Err(e) => Err(ApiError::Internal(e.into()).into_response())
     }
 }
@@ -1,35 +1,73 @@
-use bitflags::bitflags;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
 use hyper::http::response::Builder;
-use hyper::Response;
+use hyper::header::ALLOW;
+use hyper::{Response, StatusCode};
+use std::error::Error;
 
-bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-    pub struct ResponseOption: u32 {
-        const None = 0;
-        const AllowCors = 1 << 0;
-    }
+/// Body of responses, either buffered by [`full`] or streamed by any
+/// `hyper::body::Body` boxed with `BodyExt::boxed_unsync`, e.g. for Server-Sent Events
+pub type ResponseBody = UnsyncBoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+pub fn new_response() -> Builder {
+    Response::builder()
+}
+
+/// Body of `data` as a whole
+pub fn full(data: impl Into<Bytes>) -> ResponseBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
 }
 
-static mut OPTIONS: ResponseOption = ResponseOption::None;
+/// Answer of OPTIONS with `allow`ed methods of the route;
+/// [`Cors`](crate::middleware::cors::Cors) makes it a CORS preflight
+pub fn options_response(allow: &str) -> Response<ResponseBody> {
+    new_response()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, allow)
+        .body(full(Bytes::new()))
+        .unwrap()
+}
 
-pub fn set_response_option(option: ResponseOption) {
-    unsafe {
-        OPTIONS = option;
+/// Copies headers of `from` into `response`,
+/// e.g. cookies of tokens refreshed by `AccessToken::validate_authorization`
+pub fn merge_headers<B>(mut response: Response<B>, from: Response<ResponseBody>) -> Response<B> {
+    for (name, value) in from.headers() {
+        response.headers_mut().append(name, value.clone());
     }
+
+    response
 }
 
-pub fn new_response() -> Builder {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::{Body, Frame};
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
 
-    let mut builder = Response::builder();
+    /// Body of chunks, of which the length is unknown in advance
+    struct Chunks(VecDeque<&'static str>);
 
-    let options = unsafe { OPTIONS };
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = Box<dyn Error + Send + Sync>;
 
-    if (options & ResponseOption::AllowCors) != ResponseOption::AllowCors {
-        builder = builder
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*");
+        fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(Bytes::from(chunk)))))
+        }
     }
 
-    builder
+    #[tokio::test]
+    async fn streams_boxed_bodies() {
+        let body: ResponseBody = Chunks(VecDeque::from(["data: a\n\n", "data: b\n\n"])).boxed_unsync();
+        assert_eq!(body.size_hint().exact(), None);
+
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(bytes, "data: a\n\ndata: b\n\n");
+        assert_eq!(full("abc").size_hint().exact(), Some(3));
+    }
 }
@@ -1,68 +1,520 @@
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response};
+use crate::credentials::rbac::{Permission, RequiredPermission};
+use crate::error::ApiError;
+use crate::openapi::Operation;
+use crate::response::{full, options_response, ResponseBody};
+use hyper::body::{Body, Bytes, Incoming};
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Method, Request, Response};
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::future::Future;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 
 pub type FuturePreparation<'a> = Pin<Box<dyn Future<Output=Result<(), Box<dyn Error + 'a>>> + Send + 'a>>;
-pub type FutureAction<'a> = Pin<Box<dyn Future<Output=Result<Response<Full<Bytes>>, Box<dyn Error + 'a>>> + Send + 'a>>;
+pub type FutureAction<'a> = Pin<Box<dyn Future<Output=Result<Response<ResponseBody>, Box<dyn Error>>> + Send + 'a>>;
+pub type FutureLifecycle<'a> = Pin<Box<dyn Future<Output=Result<(), Box<dyn Error + 'a>>> + 'a>>;
 
-pub trait Route : Display {
+pub type Handler<'a> = Box<dyn Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a>;
+
+pub trait Route : Display + Sync {
     fn name(&self) -> &str;
     fn children(&self) -> Vec<&dyn Route>;
-    fn up(&self) -> FuturePreparation;
-    fn down(&self) -> FuturePreparation;
-    fn map(&self, req: Request<Incoming>) -> FutureAction;
+    fn up(&self) -> FuturePreparation<'_>;
+    fn down(&self) -> FuturePreparation<'_>;
+
+    /// Handlers per method; routes without any, e.g. parents of others, are not found.
+    ///
+    /// Other methods are answered with 405, OPTIONS with `Allow`, and HEAD by the handler of GET.
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+    }
+
+    /// Permission required to be routed here, checked by [`Authorize`](crate::middleware::authorize::Authorize)
+    /// before any handler. It is not inherited by children.
+    fn permission(&self) -> Option<Permission> {
+        None
+    }
+
+    /// Routes, by their `Display`, which have to be up before this one, e.g. owners of tables it refers to.
+    /// Parents are always up before their children.
+    fn dependencies(&self) -> Vec<&str> {
+        vec![]
+    }
+
+    /// Layers around handlers of this route and all of its descendants, the outermost first.
+    /// Layers of ancestors wrap these.
+    fn middleware(&self) -> Vec<Arc<dyn Middleware>> {
+        vec![]
+    }
+}
+
+/// Layer around handlers, e.g. logging, CORS or rate limiting.
+///
+/// It may answer by itself, or pass `req` on to the inner layers with [`Next::run`]
+/// and work on the response.
+pub trait Middleware : Send + Sync {
+    fn handle<'a>(&'a self, req: Request<Incoming>, next: Next<'a>) -> FutureAction<'a>;
+}
+
+/// Inner layers and the handler
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+    endpoint: Box<dyn FnOnce(Request<Incoming>) -> FutureAction<'a> + Send + 'a>,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Request<Incoming>) -> FutureAction<'a> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(req, Next { layers, endpoint: self.endpoint }),
+            None => (self.endpoint)(req)
+        }
+    }
+}
+
+/// Handlers of a route per method, built by [`Route::methods`]
+#[derive(Default)]
+pub struct Methods<'a> {
+    handlers: Vec<(Method, Handler<'a>)>,
+    operations: Vec<(Method, Operation)>,
+}
+
+impl<'a> Methods<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on<F>(mut self, method: Method, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a,
+    {
+        self.handlers.push((method, Box::new(handler)));
+        self
+    }
+
+    pub fn get<F>(self, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a,
+    {
+        self.on(Method::GET, handler)
+    }
+
+    pub fn post<F>(self, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a,
+    {
+        self.on(Method::POST, handler)
+    }
+
+    pub fn put<F>(self, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a,
+    {
+        self.on(Method::PUT, handler)
+    }
+
+    pub fn delete<F>(self, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> FutureAction<'a> + Send + Sync + 'a,
+    {
+        self.on(Method::DELETE, handler)
+    }
+
+    /// Describes the handler registered last, for the OpenAPI document
+    pub fn describe(mut self, operation: Operation) -> Self {
+        let method = match self.handlers.last() {
+            Some((method, _)) => method.clone(),
+            None => panic!("no handler to describe")
+        };
+
+        self.operations.push((method, operation));
+        self
+    }
+
+    /// Registered methods, with their descriptions if any
+    pub fn operations(&self) -> Vec<(&Method, Option<&Operation>)> {
+        self.handlers.iter()
+            .map(|(method, _)| (method, self.operations.iter().find(|(described, _)| described == method).map(|(_, operation)| operation)))
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Handler of `method`; HEAD is served by GET unless registered by itself
+    fn handler(&self, method: &Method) -> Option<&Handler<'a>> {
+        let find = |method: &Method| self.handlers.iter()
+            .find(|(registered, _)| registered == method)
+            .map(|(_, handler)| handler);
+
+        match find(method) {
+            None if method == Method::HEAD => find(&Method::GET),
+            found => found
+        }
+    }
+
+    /// Value of `Allow` header
+    fn allow(&self) -> String {
+        let mut methods = self.handlers.iter().map(|(method, _)| method.as_str()).collect::<Vec<&str>>();
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        if !methods.contains(&"OPTIONS") {
+            methods.push("OPTIONS");
+        }
+
+        methods.join(", ")
+    }
+}
+
+/// Path parameters captured by `{name}` segments of routes, inserted into extensions of the request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    /// Parameters of `req`; empty if it has not been routed
+    pub fn of<B>(req: &Request<B>) -> &PathParams {
+        static EMPTY: PathParams = PathParams { params: vec![] };
+
+        req.extensions().get::<PathParams>().unwrap_or(&EMPTY)
+    }
+
+    /// Percent-decoded value of parameter `name`
+    fn raw(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of parameter `name`, parsed as `T`
+    pub fn get<T>(&self, name: &str) -> Result<T, Box<dyn Error>>
+    where
+        T: FromStr,
+        T::Err: Error + 'static,
+    {
+        match self.raw(name) {
+            Some(value) => value.parse::<T>().map_err(|e| e.into()),
+            None => Err(format!("path parameter `{}` is missing", name).into())
+        }
+    }
 }
 
-pub fn match_route<'a>(path: &str, root: &'a dyn Route) -> Option<&'a dyn Route> {
-    let segments = path.split('/').skip(1).collect::<Vec<&str>>();
+/// Kind of a segment of route names:
+/// `name` matches itself, `{name}` or `*` any one segment, and `{*name}` all the rest
+pub enum Pattern<'a> {
+    Static(&'a str),
+    Param(Option<&'a str>),
+    CatchAll(&'a str),
+}
 
-    let mut current = root;
+impl<'a> Pattern<'a> {
+    pub fn of(name: &'a str) -> Self {
+        if name == "*" {
+            return Pattern::Param(None);
+        }
 
-    for segment in segments {
-        if current.children().len() == 0 {
-            return None;
+        match name.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(name) => Pattern::CatchAll(name),
+                None => Pattern::Param(Some(name))
+            },
+            None => Pattern::Static(name)
         }
+    }
+}
 
-        let next = current
-            .children().into_iter()
-            .filter(|child| child.name() == segment || child.name() == "*")
-            .collect::<Vec<&dyn Route>>();
+struct Node<'a> {
+    route: &'a dyn Route,
+    methods: Methods<'a>,
+    /// Layers of ancestors and of the route itself
+    layers: Vec<Arc<dyn Middleware>>,
+    statics: HashMap<&'a str, Node<'a>>,
+    param: Option<(Option<&'a str>, Box<Node<'a>>)>,
+    catch_all: Option<(&'a str, Box<Node<'a>>)>,
+}
 
-        current = next[0];
+impl<'a> Node<'a> {
+    fn compile(route: &'a dyn Route, inherited: &[Arc<dyn Middleware>]) -> Result<Self, Box<dyn Error>> {
+        let mut layers = inherited.to_vec();
+        layers.extend(route.middleware());
+
+        let mut node = Node {
+            route,
+            methods: route.methods(),
+            layers,
+            statics: HashMap::new(),
+            param: None,
+            catch_all: None,
+        };
+        let layers = node.layers.clone();
+
+        for child in route.children() {
+            let conflict = match Pattern::of(child.name()) {
+                Pattern::Static(name) => node.statics.insert(name, Node::compile(child, &layers)?).is_some(),
+                Pattern::Param(name) => node.param.replace((name, Box::new(Node::compile(child, &layers)?))).is_some(),
+                Pattern::CatchAll(name) => {
+                    if !child.children().is_empty() {
+                        return Err(format!("catch-all route {} cannot have children", child).into());
+                    }
+                    node.catch_all.replace((name, Box::new(Node::compile(child, &layers)?))).is_some()
+                }
+            };
+
+            if conflict {
+                return Err(format!("route {} conflicts with a sibling under {}", child, route).into());
+            }
+        }
+
+        Ok(node)
     }
+}
 
-    Some(current)
+/// Outcome of [`RouteTable::lookup`]
+pub enum Lookup<'t, 'a> {
+    Found(Target<'t, 'a>),
+    NotFound,
+    /// Route exists, but has no handler of the method; with `Allow` of the route
+    MethodNotAllowed(String),
+    /// OPTIONS of a route which doesn't handle it by itself; with `Allow` of the route
+    Options(String),
 }
 
-pub fn up_all<'a>(root: &'a dyn Route) -> Pin<Box<dyn Future<Output=Result<(), Box<dyn Error + 'a>>> + 'a>> {
+/// Handler found for a request
+pub struct Target<'t, 'a> {
+    route: &'a dyn Route,
+    handler: &'t Handler<'a>,
+    layers: &'t [Arc<dyn Middleware>],
+    params: PathParams,
+}
+
+impl<'t, 'a: 't> Target<'t, 'a> {
+    /// Runs the handler through layers of the route,
+    /// with captured [`PathParams`] and [`RequiredPermission`] in extensions of `req`
+    pub fn call(self, mut req: Request<Incoming>) -> FutureAction<'t> {
+        req.extensions_mut().insert(self.params);
+        if let Some(permission) = self.route.permission() {
+            req.extensions_mut().insert(RequiredPermission(permission));
+        }
+
+        let handler = self.handler;
+        let endpoint = move |req: Request<Incoming>| -> FutureAction<'t> {
+            let head = req.method() == Method::HEAD;
+            let future = handler(req);
+            if !head {
+                return future;
+            }
+
+            // Response of GET without its body, but with its length
+            Box::pin(async move {
+                let (mut parts, body) = future.await?.into_parts();
+                if let Some(length) = body.size_hint().exact() {
+                    parts.headers.insert(CONTENT_LENGTH, length.into());
+                }
+
+                Ok(Response::from_parts(parts, full(Bytes::new())))
+            })
+        };
+
+        Next { layers: self.layers, endpoint: Box::new(endpoint) }.run(req)
+    }
+}
+
+/// Trie of the route tree, compiled once on start.
+///
+/// On each segment, static names win over parameters, which win over catch-alls,
+/// regardless of the order of children.
+pub struct RouteTable<'a> {
+    root: Node<'a>,
+}
+
+impl<'a> RouteTable<'a> {
+    /// Fails if siblings are ambiguous, e.g. two parameters or two routes of the same name
+    pub fn new(root: &'a dyn Route) -> Result<Self, Box<dyn Error>> {
+        Ok(Self { root: Node::compile(root, &[])? })
+    }
+
+    /// Answers `req` with its handler, or with 404, 405 or OPTIONS through global layers
+    pub fn dispatch(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        let response = match self.lookup(req.method(), req.uri().path()) {
+            Lookup::Found(target) => return target.call(req),
+            Lookup::NotFound => ApiError::NotFound.into_response(),
+            Lookup::MethodNotAllowed(allow) => ApiError::MethodNotAllowed(allow).into_response(),
+            Lookup::Options(allow) => options_response(&allow)
+        };
+
+        let endpoint = move |_req| -> FutureAction<'_> { Box::pin(async move { Ok(response) }) };
+        Next { layers: &self.root.layers, endpoint: Box::new(endpoint) }.run(req)
+    }
+
+    /// Route of `path` for `method`.
+    ///
+    /// A trailing slash is ignored, and segments are percent-decoded before matching.
+    pub fn lookup(&self, method: &Method, path: &str) -> Lookup<'_, 'a> {
+        let path = path.strip_suffix('/').unwrap_or(path);
+
+        let mut segments = vec![];
+        if !path.is_empty() {
+            for segment in path.strip_prefix('/').unwrap_or(path).split('/') {
+                match percent_decode_str(segment).decode_utf8() {
+                    Ok(segment) if !segment.is_empty() => segments.push(segment.into_owned()),
+                    _ => return Lookup::NotFound
+                }
+            }
+        }
+
+        let mut params = PathParams::default();
+        let node = match Self::find(&self.root, &segments, &mut params) {
+            Some(node) => node,
+            None => return Lookup::NotFound
+        };
+
+        match node.methods.handler(method) {
+            Some(handler) => Lookup::Found(Target { route: node.route, handler, layers: &node.layers, params }),
+            None if method == Method::OPTIONS => Lookup::Options(node.methods.allow()),
+            None => Lookup::MethodNotAllowed(node.methods.allow())
+        }
+    }
+
+    fn find<'n>(node: &'n Node<'a>, segments: &[String], params: &mut PathParams) -> Option<&'n Node<'a>> {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            // Parents without handlers are passed over, so that a catch-all may take it instead
+            None => return if node.methods.is_empty() { None } else { Some(node) }
+        };
+
+        if let Some(route) = node.statics.get(segment.as_str()).and_then(|next| Self::find(next, rest, params)) {
+            return Some(route);
+        }
+
+        if let Some((name, next)) = &node.param {
+            let captured = params.params.len();
+            if let Some(name) = name {
+                params.params.push((name.to_string(), segment.clone()));
+            }
+            if let Some(route) = Self::find(next, rest, params) {
+                return Some(route);
+            }
+            // Backtracks, so that a catch-all may take it instead
+            params.params.truncate(captured);
+        }
+
+        node.catch_all.as_ref().filter(|(_, next)| !next.methods.is_empty()).map(|(name, next)| {
+            params.params.push((name.to_string(), segments.join("/")));
+            next.as_ref()
+        })
+    }
+}
+
+/// Routes of the tree in the order to be up: parents and [dependencies](Route::dependencies) first,
+/// otherwise depth-first. Fails on unknown dependencies or cycles.
+fn lifecycle_order(root: &dyn Route) -> Result<Vec<&dyn Route>, Box<dyn Error + '_>> {
+    fn collect<'a>(route: &'a dyn Route, parent: Option<usize>, routes: &mut Vec<(&'a dyn Route, Option<usize>)>) {
+        let index = routes.len();
+        routes.push((route, parent));
+
+        for child in route.children() {
+            collect(child, Some(index), routes);
+        }
+    }
+
+    let mut routes = vec![];
+    collect(root, None, &mut routes);
+    let names = routes.iter().map(|(route, _)| route.to_string()).collect::<Vec<_>>();
+
+    let mut requires = vec![];
+    for (route, parent) in &routes {
+        let mut required = parent.iter().copied().collect::<Vec<_>>();
+
+        for dependency in route.dependencies() {
+            let before = required.len();
+            required.extend((0..names.len()).filter(|&i| names[i] == dependency));
+
+            if required.len() == before {
+                return Err(format!("`{}` depends on unknown route `{}`", route, dependency).into());
+            }
+        }
+
+        requires.push(required);
+    }
+
+    let mut done = vec![false; routes.len()];
+    let mut order = vec![];
+    while order.len() < routes.len() {
+        let next = (0..routes.len())
+            .find(|&i| !done[i] && requires[i].iter().all(|&j| done[j]));
+
+        match next {
+            Some(i) => {
+                done[i] = true;
+                order.push(routes[i].0);
+            }
+            None => {
+                let cycle = (0..routes.len()).filter(|&i| !done[i]).map(|i| names[i].as_str()).collect::<Vec<_>>();
+                return Err(format!("routes depend on each other: {}", cycle.join(", ")).into());
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+/// Runs `up` of all routes in the tree, in [`lifecycle_order`].
+///
+/// If one fails, routes already up run `down` in reverse order, and the error names the route.
+pub fn up_all<'a>(root: &'a dyn Route) -> FutureLifecycle<'a> {
     Box::pin(async move {
+        let order = lifecycle_order(root)?;
+
+        for (i, route) in order.iter().enumerate() {
+            println!("Initialise {}...", route);
+
+            let failure = match route.up().await {
+                Ok(()) => continue,
+                Err(e) => format!("`{}` failed to initialise: {}", route, e)
+            };
 
-        println!("Initialise {}...", root);
-        root.up().await?;
+            for route in order[..i].iter().rev() {
+                println!("Roll back {}...", route);
 
-        for child in root.children() {
-            up_all(child).await?;
+                if let Err(e) = route.down().await {
+                    eprintln!("Failed to roll back {}: {}", route, e);
+                }
+            }
+
+            return Err(failure.into());
         }
 
-        return Ok(())
+        Ok(())
     })
 }
 
-pub fn down_all<'a>(root: &'a dyn Route) -> Pin<Box<dyn Future<Output=Result<(), Box<dyn Error + 'a>>> + 'a>> {
+/// Runs `down` of all routes in the tree, in reverse of [`lifecycle_order`].
+///
+/// Failures don't stop the others from being finalised; the error names all failed routes.
+pub fn down_all<'a>(root: &'a dyn Route) -> FutureLifecycle<'a> {
     Box::pin(async move {
+        let order = lifecycle_order(root)?;
+        let mut failures = vec![];
 
-        println!("Finalise {}...", root);
-        root.down().await?;
+        for route in order.iter().rev() {
+            println!("Finalise {}...", route);
 
-        for child in root.children() {
-            down_all(child).await?;
+            if let Err(e) = route.down().await {
+                failures.push(format!("`{}` failed to finalise: {}", route, e));
+            }
         }
 
-        return Ok(())
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; ").into())
+        }
     })
 }
 
@@ -89,20 +541,16 @@ mod tests {
             vec![&RouteA {}]
         }
 
-        fn up(&mut self) -> FuturePreparation
-        {
+        fn up(&self) -> FuturePreparation<'_> {
             Box::pin(async { Ok(()) })
         }
 
-        fn down(&mut self) -> FuturePreparation
-        {
+        fn down(&self) -> FuturePreparation<'_> {
             Box::pin(async { Ok(()) })
         }
 
-        fn map(&self, req: Request<Incoming>) -> FutureAction {
-            Box::pin(async {
-                Ok(Response::builder().body(Full::from(Bytes::new())).unwrap())
-            })
+        fn methods(&self) -> Methods<'_> {
+            Methods::new().get(|_req| ok())
         }
     }
 
@@ -121,22 +569,16 @@ mod tests {
             vec![&RouteAB {}]
         }
 
-        async fn up(&mut self) -> Result<(), Box<dyn Error>>
-        where
-            Self: Sized
-        {
-            Ok(())
+        fn up(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
         }
 
-        async fn down(&mut self) -> Result<(), Box<dyn Error>>
-        where
-            Self: Sized
-        {
-            Ok(())
+        fn down(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
         }
 
-        async fn map(&self, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Box<dyn Error>> {
-            Ok(Response::builder().body(Full::from(Bytes::new())).unwrap())
+        fn methods(&self) -> Methods<'_> {
+            Methods::new().get(|_req| ok())
         }
     }
 
@@ -152,31 +594,262 @@ mod tests {
         }
 
         fn children(&self) -> Vec<&dyn Route> {
-            Vec::new()
+            vec![&ID_ROUTE, &ME_ROUTE, &REST_ROUTE]
         }
 
-        fn up(&mut self) -> FuturePreparation {
+        fn up(&self) -> FuturePreparation<'_> {
             Box::pin(async { Ok(()) })
         }
 
-        fn down(&mut self) -> FuturePreparation {
+        fn down(&self) -> FuturePreparation<'_> {
             Box::pin(async { Ok(()) })
         }
 
-        fn map(&self, req: Request<Incoming>) -> FutureAction {
-            Box::pin(async { Ok(Response::builder().body(Full::from(Bytes::new())).unwrap()) })
+        fn methods(&self) -> Methods<'_> {
+            Methods::new().get(|_req| ok())
+        }
+    }
+
+    fn ok<'a>() -> FutureAction<'a> {
+        Box::pin(async { Ok(Response::builder().body(full(Bytes::from("body"))).unwrap()) })
+    }
+
+    /// Routes of `/a/b/{id}/c/{sid}`, `/a/b/{id}/files/{*path}`, `/a/b/me` (PUT only) and `/a/b/{*rest}`;
+    /// `/a/b/{id}/c` has no handler
+    struct NamedRoute {
+        name: &'static str,
+        children: &'static [NamedRoute],
+        methods: &'static [Method],
+    }
+
+    const fn named(name: &'static str, children: &'static [NamedRoute]) -> NamedRoute {
+        NamedRoute { name, children, methods: &[Method::GET] }
+    }
+
+    static ID_ROUTE: NamedRoute = named("{id}", &[
+        NamedRoute { name: "c", children: &[named("{sid}", &[])], methods: &[] },
+        named("files", &[named("{*path}", &[])]),
+    ]);
+    static ME_ROUTE: NamedRoute = NamedRoute { name: "me", children: &[], methods: &[Method::PUT] };
+    static REST_ROUTE: NamedRoute = named("{*rest}", &[]);
+
+    impl Display for NamedRoute {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "NamedRoute({})", self.name)
+        }
+    }
+
+    impl Route for NamedRoute {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn children(&self) -> Vec<&dyn Route> {
+            self.children.iter().map(|child| child as &dyn Route).collect()
+        }
+
+        fn up(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn down(&self) -> FuturePreparation<'_> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn methods(&self) -> Methods<'_> {
+            self.methods.iter().fold(Methods::new(), |methods, method| methods.on(method.clone(), |_req| ok()))
+        }
+    }
+
+    /// Name of the route found for GET `path`, and captured parameters
+    fn lookup(path: &str) -> Option<(String, PathParams)> {
+        match RouteTable::new(&RootRoute {}).unwrap().lookup(&Method::GET, path) {
+            Lookup::Found(target) => Some((target.route.name().to_string(), target.params)),
+            _ => None
         }
     }
 
     #[test]
     fn test_route_a() {
-        let req = match_route("/a", &RootRoute {}).unwrap();
-        assert_eq!(req.name(), "a");
+        let (name, _) = lookup("/a").unwrap();
+        assert_eq!(name, "a");
     }
 
     #[test]
     fn test_route_ab() {
-        let req = match_route("/a/b", &RootRoute {}).unwrap();
-        assert_eq!(req.name(), "b");
+        let (name, _) = lookup("/a/b").unwrap();
+        assert_eq!(name, "b");
+    }
+
+    #[test]
+    fn test_route_params() {
+        let (name, params) = lookup("/a/b/42/c/caf%C3%A9").unwrap();
+
+        assert_eq!(name, "{sid}");
+        assert_eq!(params.get::<i32>("id").unwrap(), 42);
+        assert_eq!(params.get::<String>("sid").unwrap(), "café");
+        assert!(params.get::<i32>("sid").is_err());
+        assert!(params.get::<String>("none").is_err());
+    }
+
+    #[test]
+    fn test_route_precedence() {
+        let root = RootRoute {};
+        let table = RouteTable::new(&root).unwrap();
+        assert!(matches!(table.lookup(&Method::PUT, "/a/b/me"), Lookup::Found(target) if target.params == PathParams::default()));
+
+        let (name, params) = lookup("/a/b/42/files/x/y.txt").unwrap();
+        assert_eq!(name, "{*path}");
+        assert_eq!(params.get::<String>("path").unwrap(), "x/y.txt");
+
+        // `{id}` has no `d`, so the catch-all takes it, without parameters of `{id}`
+        let (name, params) = lookup("/a/b/42/d").unwrap();
+        assert_eq!(name, "{*rest}");
+        assert_eq!(params.get::<String>("rest").unwrap(), "42/d");
+        assert!(params.get::<String>("id").is_err());
+    }
+
+    #[test]
+    fn test_route_normalization() {
+        assert_eq!(lookup("/a/b/").unwrap().0, "b");
+        assert_eq!(lookup("/%61").unwrap().0, "a");
+        assert_eq!(lookup("/").unwrap().0, "");
+        assert!(lookup("/a//b").is_none());
+        assert!(lookup("/a/%FF").is_none());
+    }
+
+    #[test]
+    fn test_route_not_found() {
+        assert!(lookup("/b").is_none());
+        assert!(lookup("/a/c").is_none());
+
+        // `c` has no handler, so the catch-all takes it
+        assert_eq!(lookup("/a/b/42/c").unwrap().0, "{*rest}");
+    }
+
+    #[test]
+    fn test_route_methods() {
+        let root = RootRoute {};
+        let table = RouteTable::new(&root).unwrap();
+
+        assert!(matches!(table.lookup(&Method::GET, "/a/b/me"), Lookup::MethodNotAllowed(allow) if allow == "PUT, OPTIONS"));
+        assert!(matches!(table.lookup(&Method::HEAD, "/a/b/me"), Lookup::MethodNotAllowed(_)));
+        assert!(matches!(table.lookup(&Method::OPTIONS, "/a/b"), Lookup::Options(allow) if allow == "GET, HEAD, OPTIONS"));
+        assert!(matches!(table.lookup(&Method::HEAD, "/a/b"), Lookup::Found(_)));
+    }
+
+    #[test]
+    fn test_route_conflict() {
+        static CONFLICT: NamedRoute = named("", &[named("{id}", &[]), named("{name}", &[])]);
+
+        assert!(RouteTable::new(&CONFLICT).is_err());
+    }
+
+    /// Route recording its lifecycle into `log`, which fails to be up if `fails`
+    struct LifecycleRoute {
+        name: &'static str,
+        children: Vec<LifecycleRoute>,
+        dependencies: Vec<&'static str>,
+        fails: bool,
+        log: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl LifecycleRoute {
+        fn new(name: &'static str, log: &Arc<std::sync::Mutex<Vec<String>>>) -> Self {
+            Self { name, children: vec![], dependencies: vec![], fails: false, log: log.clone() }
+        }
+    }
+
+    impl Display for LifecycleRoute {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.name)
+        }
+    }
+
+    impl Route for LifecycleRoute {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn children(&self) -> Vec<&dyn Route> {
+            self.children.iter().map(|child| child as &dyn Route).collect()
+        }
+
+        fn up(&self) -> FuturePreparation<'_> {
+            Box::pin(async move {
+                if self.fails {
+                    return Err("relation already exists".into());
+                }
+
+                self.log.lock().unwrap().push(format!("up {}", self.name));
+                Ok(())
+            })
+        }
+
+        fn down(&self) -> FuturePreparation<'_> {
+            Box::pin(async move {
+                self.log.lock().unwrap().push(format!("down {}", self.name));
+                Ok(())
+            })
+        }
+
+        fn dependencies(&self) -> Vec<&str> {
+            self.dependencies.clone()
+        }
+    }
+
+    /// `root` with children `a` and `b`, of which `a` depends on `b`
+    fn lifecycle_tree(log: &Arc<std::sync::Mutex<Vec<String>>>) -> LifecycleRoute {
+        let mut a = LifecycleRoute::new("a", log);
+        a.dependencies = vec!["b"];
+        a.children = vec![LifecycleRoute::new("c", log)];
+
+        let mut root = LifecycleRoute::new("root", log);
+        root.children = vec![a, LifecycleRoute::new("b", log)];
+        root
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_order() {
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let root = lifecycle_tree(&log);
+
+        up_all(&root).await.unwrap();
+        down_all(&root).await.unwrap();
+
+        assert_eq!(*log.lock().unwrap(), [
+            "up root", "up b", "up a", "up c",
+            "down c", "down a", "down b", "down root"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_rollback() {
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut root = lifecycle_tree(&log);
+        root.children[0].children[0].fails = true;
+
+        let error = up_all(&root).await.unwrap_err().to_string();
+
+        assert!(error.contains("`c`") && error.contains("relation already exists"));
+        assert_eq!(*log.lock().unwrap(), [
+            "up root", "up b", "up a",
+            "down a", "down b", "down root"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_invalid_dependencies() {
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut root = lifecycle_tree(&log);
+        root.children[1].dependencies = vec!["c"];
+        assert!(up_all(&root).await.is_err());
+
+        let mut root = lifecycle_tree(&log);
+        root.children[1].dependencies = vec!["d"];
+        assert!(up_all(&root).await.is_err());
+
+        assert!(log.lock().unwrap().is_empty());
     }
 }
@@ -1,3 +1,10 @@
 pub mod account;
 pub mod root;
-pub mod login;
\ No newline at end of file
+pub mod login;
+pub mod token;
+pub mod totp;
+pub mod oidc;
+pub mod sessions;
+pub mod api_keys;
+pub mod admin;
+pub mod openapi;
\ No newline at end of file
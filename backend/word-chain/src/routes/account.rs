@@ -1,18 +1,28 @@
+use crate::audit::{Audit, AuditEvent};
+use crate::credentials::session::Device;
 use crate::credentials::tokens::AccessToken;
-use crate::encrypt::Salt;
-use crate::request::read_body;
-use crate::response::new_response;
-use crate::route::{FutureAction, FuturePreparation, Route};
-use http_body_util::Full;
+use crate::error::ApiError;
+use crate::encrypt::{constant_time_eq, Argon2id, Salt, Verification};
+use crate::extract::{self, FieldErrors, Validate};
+use crate::response::{full, new_response};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, PathParams, Route};
+use crate::routes::api_keys::ApiKeysRoute;
+use crate::routes::sessions::SessionsRoute;
+use crate::routes::totp::TotpRoute;
 use hyper::body::{Bytes, Incoming};
-use hyper::header::LOCATION;
-use hyper::{Method, Request, StatusCode};
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Request, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
+use tokio_postgres::error::SqlState;
 use tokio_postgres::{Client, Row};
 
 pub struct AccountRoute {
+    totp_route: TotpRoute,
+    sessions_route: SessionsRoute,
+    api_keys_route: ApiKeysRoute,
     info_route: AccountInfoRoute,
     client: Arc<Client>,
 }
@@ -21,17 +31,54 @@ pub struct AccountInfoRoute {
     client: Arc<Client>,
 }
 
+/// Names of the static children of `/account`, which take precedence over `/account/{id}`
+const RESERVED_IDS: [&str; 3] = ["keys", "sessions", "totp"];
+
 #[derive(Debug, Deserialize)]
 struct AccountCreationDTO {
     id: String,
     password: String,
 }
 
+impl Validate for AccountCreationDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.id.is_empty(), "id", "must not be empty");
+        // NOTE: `:` is reserved for accounts created by OpenID Connect login
+        errors.check(!self.id.contains(':'), "id", "`:` is not allowed");
+        errors.check(!RESERVED_IDS.contains(&self.id.as_str()), "id", "is reserved");
+        errors.check(!self.password.is_empty(), "password", "must not be empty");
+    }
+}
+
+impl Schema for AccountCreationDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "string", "minLength": 1, "pattern": "^[^:]*$", "not": { "enum": RESERVED_IDS } },
+                "password": { "type": "string", "minLength": 1 }
+            },
+            "required": ["id", "password"]
+        })
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct AccountViewDTO {
     id: String
 }
 
+impl Schema for AccountViewDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": { "id": { "type": "string" } },
+            "required": ["id"]
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct AccountRow {
     id: String,
     salt: String,
@@ -58,6 +105,38 @@ impl AccountRow {
     pub fn passhash(&self) -> &str {
         &self.passhash
     }
+
+    /// Verifies `password` against both Argon2id PHC strings
+    /// and legacy SHA3 hashes; the latter are always [`Verification::Outdated`]
+    pub fn verify_password(&self, password: &str) -> Verification {
+        if Argon2id::is_phc(self.passhash()) {
+            return Argon2id::verify(password, self.passhash());
+        }
+
+        if constant_time_eq(&self.salt().salt(password), self.passhash()) {
+            Verification::Outdated
+        } else {
+            Verification::Mismatched
+        }
+    }
+
+    /// Re-hashes `password` with current Argon2id parameters.
+    /// Failure is not fatal, since the user is already authenticated with the old hash.
+    pub async fn upgrade_passhash(&self, password: &str, client: &Client) {
+        let passhash = match Argon2id::hash(password) {
+            Ok(passhash) => passhash,
+            Err(e) => {
+                eprintln!("Failed to upgrade password hash of `{}`: {}", self.id(), e);
+                return;
+            }
+        };
+
+        if let Err(e) = client.execute(
+            "UPDATE accounts SET salt = '', password = $2 WHERE id = $1 AND password = $3;",
+            &[&self.id(), &passhash, &self.passhash()]).await {
+            eprintln!("Failed to upgrade password hash of `{}`: {}", self.id(), e);
+        }
+    }
 }
 
 impl AccountViewDTO {
@@ -68,7 +147,13 @@ impl AccountViewDTO {
 
 impl AccountRoute {
     pub fn new(client: Arc<Client>) -> Self {
-        Self { info_route: AccountInfoRoute::new(client.clone()), client }
+        Self {
+            totp_route: TotpRoute::new(client.clone()),
+            sessions_route: SessionsRoute::new(client.clone()),
+            api_keys_route: ApiKeysRoute::new(client.clone()),
+            info_route: AccountInfoRoute::new(client.clone()),
+            client
+        }
     }
 }
 
@@ -90,10 +175,10 @@ impl Route for AccountRoute {
     }
 
     fn children(&self) -> Vec<&dyn Route> {
-        vec![&self.info_route]
+        vec![&self.totp_route, &self.sessions_route, &self.api_keys_route, &self.info_route]
     }
 
-    fn up(&self) -> FuturePreparation
+    fn up(&self) -> FuturePreparation<'_>
     {
         Box::pin(async move {
             self.client.execute(r#"
@@ -108,7 +193,7 @@ impl Route for AccountRoute {
         })
     }
 
-    fn down(&self) -> FuturePreparation
+    fn down(&self) -> FuturePreparation<'_>
     {
         Box::pin(async move {
             /*
@@ -121,74 +206,101 @@ impl Route for AccountRoute {
         })
     }
 
-    fn map(&self, req: Request<Incoming>) -> FutureAction
-    {
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.show(req))
+            .describe(Operation::new("The authorized account, also by API keys")
+                .authorized()
+                .json(200, "The account", AccountViewDTO::schema()))
+            .post(|req| self.create(req))
+            .describe(Operation::new("Create an account")
+                .body::<AccountCreationDTO>()
+                .response(201, "Created, at `Location`"))
+            .delete(|req| self.delete(req))
+            .describe(Operation::new("Delete the authorized account, revoking all of its tokens")
+                .authorized()
+                .response(200, "Deleted"))
+    }
+}
+
+impl AccountRoute {
+    /// The authorized account itself, which bots can check their API keys with
+    fn show(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::credentials(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+
+            let json = serde_json::to_string(&AccountViewDTO::new(authenticated.account().id()))?;
+
+            Ok(authenticated.respond(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(Bytes::from(json)))
+                .unwrap()))
+        })
+    }
+
+    fn create(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let device = Device::from(&req);
+            let creation = match extract::body::<AccountCreationDTO>(req).await {
+                Ok(creation) => creation,
+                Err(e) => return Ok(e)
+            };
+
+            let passhash = Argon2id::hash(&creation.password)?;
+
+            // NOTE: Salt is embedded in PHC string; `salt` column is only for legacy hashes
+            if let Err(error) = self.client.execute(
+                "INSERT INTO accounts (id, salt, password) VALUES ($1, '', $2);",
+                &[&creation.id, &passhash]).await {
+
+                // NOTE: Only a duplicated identifier is the client's fault
+                return match error.code() {
+                    Some(&SqlState::UNIQUE_VIOLATION) => Err(ApiError::AccountExists.into()),
+                    _ => Err(error.into())
+                };
+            }
+
+            Audit::record(AuditEvent::AccountCreated, Some(&creation.id), &device, None, &self.client).await;
+
+            Ok(new_response()
+                .status(StatusCode::CREATED)
+                .header(LOCATION, format!("/account/{}", creation.id))
+                .body(full(Bytes::new()))
+                .unwrap())
+        })
+    }
+
+    fn delete(&self, req: Request<Incoming>) -> FutureAction<'_> {
         Box::pin(async move {
-            match req.method() {
-                &Method::POST => {
-                    let body = match read_body(req.into_body()).await {
-                        Ok(body) => body,
-                        Err(e) => return Ok(e)
-                    };
-
-                    let creation = match serde_urlencoded::from_bytes::<AccountCreationDTO>(&body) {
-                        Ok(creation) => creation,
-                        Err(error) => return Ok(new_response()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Full::from(Bytes::from(error.to_string())))
-                            .unwrap())
-                    };
-
-                    println!("{}", creation.password);
-
-                    let salt = Salt::new();
-
-                    if let Err(error) = self.client.execute(
-                        "INSERT INTO accounts (id, salt, password) VALUES ($1, $2, $3);",
-                        &[&creation.id, &salt.value(), &salt.salt(&creation.password)]).await {
-
-                        // NOTE: Failed to insert row: Maybe duplicated identifier?
-                        return Ok(new_response()
-                            .status(StatusCode::CONFLICT)
-                            .body(Full::from(Bytes::from(error.to_string())))
-                            .unwrap());
-                    }
-
-                    Ok(new_response()
-                        .status(StatusCode::CREATED)
-                        .header(LOCATION, format!("/account/{}", creation.id))
-                        .body(Full::from(Bytes::new()))
-                        .unwrap())
-                },
-                &Method::DELETE => {
-                    let (account, _) = match AccessToken::validate_authorization(&req, &self.client).await {
-                        Ok(response) => response,
-                        Err(e) => return Ok(e)
-                    };
-
-                    if let Err(_) = self.client.execute(
-                        "DELETE FROM accounts WHERE id = $1;",
-                        &[&account.id()]).await {
-
-                        // Q: WHY DON'T WE HANDLE ERROR?
-                        // A: IT'S SAFE TO IGNORE
-
-                        // Although an authorized account should exist,
-                        // If deleting the account failed, It may be a race-condition.
-                        // But, we don't have to lock function to prevent it.
-                        // If the account is authorized in any way,
-                        // The expected result (account deleted) will be occurred
-                    }
-
-                    // NOTE: If user uses access-token after account deleted,
-                    // User can access to account that user doesn't own
-                    Ok(new_response().body(Full::from(Bytes::new())).unwrap())
-                },
-                _ => Ok(new_response()
-                    .status(StatusCode::METHOD_NOT_ALLOWED)
-                    .body(Full::from(Bytes::new()))
-                    .unwrap())
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            AccessToken::revoke_account(account.id(), &self.client).await?;
+            Audit::record(AuditEvent::AccountDeleted, Some(account.id()), &Device::from(&req), None, &self.client).await;
+
+            if self.client.execute(
+                "DELETE FROM accounts WHERE id = $1;",
+                &[&account.id()]).await.is_err() {
+
+                // Q: WHY DON'T WE HANDLE ERROR?
+                // A: IT'S SAFE TO IGNORE
+
+                // Although an authorized account should exist,
+                // If deleting the account failed, It may be a race-condition.
+                // But, we don't have to lock function to prevent it.
+                // If the account is authorized in any way,
+                // The expected result (account deleted) will be occurred
             }
+
+            // NOTE: Tokens issued before deletion are revoked above,
+            // so they can't access an account re-created with the same id
+            Ok(new_response().body(full(Bytes::new())).unwrap())
         })
     }
 }
@@ -200,47 +312,67 @@ impl Display for AccountInfoRoute {
 }
 
 impl Route for AccountInfoRoute {
-    fn name(&self) -> &str { "*" }
+    fn name(&self) -> &str { "{id}" }
 
     fn children(&self) -> Vec<&dyn Route> { vec![] }
 
-    fn up(&self) -> FuturePreparation
+    fn up(&self) -> FuturePreparation<'_>
     { Box::pin(async move { Ok(()) }) }
 
-    fn down(&self) -> FuturePreparation
+    fn down(&self) -> FuturePreparation<'_>
     { Box::pin(async move { Ok(()) }) }
 
-    fn map(&self, req: Request<Incoming>) -> FutureAction
-    {
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.show(req))
+            .describe(Operation::new("Public view of an account")
+                .json(200, "The account", AccountViewDTO::schema())
+                .response(404, "No such account"))
+    }
+}
+
+impl AccountInfoRoute {
+    fn show(&self, req: Request<Incoming>) -> FutureAction<'_> {
         Box::pin(async move {
-            let id = req.uri().path().split('/').last().unwrap();
-
-            if req.method() == Method::GET {
-                let row = match self.client.query_one(
-                    "SELECT * FROM accounts WHERE id = $1;",
-                    &[&id]).await {
-                    Ok(row) => row,
-                    Err(_) => return Ok(new_response()
-                        .status(StatusCode::NOT_FOUND)
-                        .body(Full::from(Bytes::new()))
-                        .unwrap())
-                };
+            let id: String = PathParams::of(&req).get("id")?;
 
-                let dto = AccountViewDTO::new(AccountRow::from(row).id());
+            let row = match self.client.query_opt(
+                "SELECT * FROM accounts WHERE id = $1;",
+                &[&id]).await? {
+                Some(row) => row,
+                None => return Err(ApiError::NotFound.into())
+            };
 
-                let json = match serde_json::to_string::<AccountViewDTO>(&dto) {
-                    Ok(json) => json,
-                    Err(e) => return Err(e.into())
-                };
+            let dto = AccountViewDTO::new(AccountRow::from(row).id());
 
-                Ok(new_response().body(Full::from(Bytes::from(json))).unwrap())
-            }
-            else {
-                Ok(new_response()
-                    .status(StatusCode::METHOD_NOT_ALLOWED)
-                    .body(Full::from(Bytes::new()))
-                    .unwrap())
-            }
+            let json = match serde_json::to_string::<AccountViewDTO>(&dto) {
+                Ok(json) => json,
+                Err(e) => return Err(e.into())
+            };
+
+            Ok(new_response().body(full(Bytes::from(json))).unwrap())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(id: &str) -> FieldErrors {
+        let mut errors = FieldErrors::default();
+        AccountCreationDTO { id: id.to_string(), password: "password".to_string() }.validate(&mut errors);
+
+        errors
+    }
+
+    #[test]
+    fn test_reserved_ids() {
+        for id in RESERVED_IDS {
+            assert!(!errors(id).is_empty(), "`{}` must be reserved", id);
+        }
+
+        assert!(errors("alice").is_empty());
+        assert!(errors("keys2").is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::audit::{Audit, AuditEntry, AuditEvent};
+use crate::credentials::rbac::{builtin_roles, Permission, Roles};
+use crate::credentials::session::Device;
+use crate::credentials::tokens::Authorized;
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::response::{full, new_response, ResponseBody};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, PathParams, Route};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Administration of the service.
+///
+/// The first admin is granted with `BOOTSTRAP_ADMIN`, the id of an existing account, on start.
+pub struct AdminRoute {
+    roles_route: AdminRolesRoute,
+    audit_route: AuditRoute,
+    client: Arc<Client>,
+}
+
+/// Audit log, the newest first: GET `/admin/audit?before={id}&limit={n}&account={id}&event={event}`
+pub struct AuditRoute {
+    client: Arc<Client>,
+}
+
+pub struct AdminRolesRoute {
+    account_roles_route: AccountRolesRoute,
+}
+
+/// Roles of an account: listing (GET), granting (POST) and revoking (DELETE) `/admin/roles/{account}`
+pub struct AccountRolesRoute {
+    client: Arc<Client>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoleDTO {
+    role: String,
+}
+
+impl Schema for RoleDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": { "role": { "type": "string", "minLength": 1 } },
+            "required": ["role"]
+        })
+    }
+}
+
+impl Validate for RoleDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.role.is_empty(), "role", "must not be empty");
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQueryDTO {
+    before: Option<i64>,
+    limit: Option<i64>,
+    account: Option<String>,
+    event: Option<String>,
+}
+
+impl Schema for AuditQueryDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "before": { "type": "integer", "minimum": 1, "description": "`next` of the previous page" },
+                "limit": { "type": "integer", "minimum": 1, "maximum": 200, "default": 50 },
+                "account": { "type": "string" },
+                "event": { "type": "string" }
+            }
+        })
+    }
+}
+
+impl Validate for AuditQueryDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(self.before.is_none_or(|before| before > 0), "before", "must be positive");
+    }
+}
+
+impl AdminRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self {
+            roles_route: AdminRolesRoute {
+                account_roles_route: AccountRolesRoute { client: client.clone() },
+            },
+            audit_route: AuditRoute { client: client.clone() },
+            client,
+        }
+    }
+}
+
+impl Display for AdminRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::admin::AdminRoute")
+    }
+}
+
+impl Route for AdminRoute {
+    fn name(&self) -> &str {
+        "admin"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.roles_route, &self.audit_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS role_permissions (
+                role       TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+                permission TEXT NOT NULL,
+                PRIMARY KEY (role, permission)
+            );
+            CREATE TABLE IF NOT EXISTS account_roles (
+                account TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                role    TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+                PRIMARY KEY (account, role)
+            );
+            "#).await?;
+
+            for (role, permissions) in builtin_roles() {
+                self.client.execute(
+                    "INSERT INTO roles (name) VALUES ($1) ON CONFLICT DO NOTHING;",
+                    &[&role]).await?;
+
+                for permission in permissions {
+                    self.client.execute(
+                        "INSERT INTO role_permissions (role, permission) VALUES ($1, $2) ON CONFLICT DO NOTHING;",
+                        &[&role, &permission.as_str()]).await?;
+                }
+            }
+
+            if let Ok(admin) = std::env::var("BOOTSTRAP_ADMIN") {
+                let exists = self.client.query_opt(
+                    "SELECT 1 FROM accounts WHERE id = $1;",
+                    &[&admin]).await?.is_some();
+
+                if !exists {
+                    eprintln!("BOOTSTRAP_ADMIN `{}` doesn't exist; create the account and restart", admin);
+                } else if Roles::grant(&admin, "admin", &self.client).await? {
+                    eprintln!("Granted admin to `{}`", admin);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        vec!["word_chain::routes::account::AccountRoute"]
+    }
+}
+
+impl Display for AuditRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::admin::AuditRoute")
+    }
+}
+
+impl Route for AuditRoute {
+    fn name(&self) -> &str {
+        "audit"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            // NOTE: Rows have no foreign key, so that they outlive deleted accounts
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id          BIGSERIAL PRIMARY KEY,
+                occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                event       TEXT NOT NULL,
+                account     TEXT,
+                address     TEXT,
+                user_agent  TEXT,
+                detail      TEXT
+            );
+            ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS actor TEXT;
+            ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS method TEXT;
+            CREATE INDEX IF NOT EXISTS audit_log_account ON audit_log (account, id);
+            CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS TRIGGER AS $$
+            BEGIN
+                RAISE EXCEPTION 'audit_log is append-only';
+            END;
+            $$ LANGUAGE plpgsql;
+            DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+            CREATE TRIGGER audit_log_append_only
+                BEFORE UPDATE OR DELETE ON audit_log
+                FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+            DROP TRIGGER IF EXISTS audit_log_no_truncate ON audit_log;
+            CREATE TRIGGER audit_log_no_truncate
+                BEFORE TRUNCATE ON audit_log
+                FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+            "#).await?;
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.list(req))
+            .describe(Operation::new("Audit log, the latest first")
+                .query::<AuditQueryDTO>()
+                .json(200, "A page of entries", serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "entries": { "type": "array", "items": AuditEntry::schema() },
+                        "next": { "type": ["integer", "null"], "description": "`before` of the next page, if any" }
+                    },
+                    "required": ["entries", "next"]
+                })))
+    }
+
+    fn permission(&self) -> Option<Permission> {
+        Some(Permission::ReadAudit)
+    }
+}
+
+impl AuditRoute {
+    fn list(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let query = match extract::query::<AuditQueryDTO>(&req) {
+                Ok(query) => query,
+                Err(errors) => return Ok(errors.into_response())
+            };
+
+            let limit = query.limit.unwrap_or(50).clamp(1, 200);
+            let entries = Audit::query(
+                query.before, limit, query.account.as_deref(), query.event.as_deref(), &self.client).await?;
+
+            // Cursor of the next page, if this page is full
+            let next = if entries.len() as i64 == limit { entries.last().map(|entry| entry.id()) } else { None };
+            let json = serde_json::json!({ "entries": entries, "next": next });
+
+            Ok(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(Bytes::from(json.to_string())))
+                .unwrap())
+        })
+    }
+}
+
+impl Display for AdminRolesRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::admin::AdminRolesRoute")
+    }
+}
+
+impl Route for AdminRolesRoute {
+    fn name(&self) -> &str {
+        "roles"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.account_roles_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Display for AccountRolesRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::admin::AccountRolesRoute")
+    }
+}
+
+impl Route for AccountRolesRoute {
+    fn name(&self) -> &str {
+        "{account}"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.list(req))
+            .describe(Operation::new("Roles of an account")
+                .json(200, "The roles", serde_json::json!({ "type": "array", "items": { "type": "string" } })))
+            .post(|req| self.grant(req))
+            .describe(Operation::new("Grant a role to an account")
+                .body::<RoleDTO>()
+                .response(204, "Granted")
+                .response(404, "No such account or role, or already granted"))
+            .delete(|req| self.revoke(req))
+            .describe(Operation::new("Revoke a role of an account")
+                .body::<RoleDTO>()
+                .response(204, "Revoked")
+                .response(404, "Not granted"))
+    }
+
+    fn permission(&self) -> Option<Permission> {
+        Some(Permission::ManageRoles)
+    }
+}
+
+/// Role named in the body of `req`
+async fn read_role(req: Request<Incoming>) -> Result<String, Response<ResponseBody>> {
+    Ok(extract::body::<RoleDTO>(req).await?.role)
+}
+
+/// Admin who requested `req`, authorized by [`Authorize`](crate::middleware::authorize::Authorize)
+fn actor_of(req: &Request<Incoming>) -> Option<String> {
+    req.extensions().get::<Authorized>().map(|Authorized(account)| account.id().to_string())
+}
+
+fn changed(changed: bool) -> Response<ResponseBody> {
+    if !changed {
+        return ApiError::NotFound.into_response();
+    }
+
+    new_response()
+        .status(StatusCode::NO_CONTENT)
+        .body(full(Bytes::new()))
+        .unwrap()
+}
+
+impl AccountRolesRoute {
+    fn list(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let account: String = PathParams::of(&req).get("account")?;
+            let roles = Roles::of(&account, &self.client).await?;
+
+            Ok(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(Bytes::from(serde_json::to_string(&roles)?)))
+                .unwrap())
+        })
+    }
+
+    fn grant(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let account: String = PathParams::of(&req).get("account")?;
+            let (actor, device) = (actor_of(&req), Device::from(&req));
+            let role = match read_role(req).await {
+                Ok(role) => role,
+                Err(e) => return Ok(e)
+            };
+
+            let granted = Roles::grant(&account, &role, &self.client).await?;
+            if granted {
+                Audit::record_by(AuditEvent::RoleGranted, actor.as_deref(), Some(&account), &device, Some(&role), &self.client).await;
+            }
+
+            Ok(changed(granted))
+        })
+    }
+
+    fn revoke(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let account: String = PathParams::of(&req).get("account")?;
+            let (actor, device) = (actor_of(&req), Device::from(&req));
+            let role = match read_role(req).await {
+                Ok(role) => role,
+                Err(e) => return Ok(e)
+            };
+
+            let revoked = Roles::revoke(&account, &role, &self.client).await?;
+            if revoked {
+                Audit::record_by(AuditEvent::RoleRevoked, actor.as_deref(), Some(&account), &device, Some(&role), &self.client).await;
+            }
+
+            Ok(changed(revoked))
+        })
+    }
+}
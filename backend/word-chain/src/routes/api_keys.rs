@@ -0,0 +1,238 @@
+use crate::audit::{Audit, AuditEvent};
+use crate::credentials::api_key::{prefix_of, ApiKey, SCOPES};
+use crate::credentials::session::Device;
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::response::{full, new_response, ResponseBody};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, PathParams, Route};
+use chrono::TimeDelta;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Listing (GET) and creation (POST) of API keys of the authorized account
+pub struct ApiKeysRoute {
+    api_key_route: ApiKeyRoute,
+    client: Arc<Client>,
+}
+
+/// Revocation of an API key (DELETE `/account/keys/{prefix}`)
+pub struct ApiKeyRoute {
+    client: Arc<Client>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyCreationDTO {
+    name: String,
+    /// Space-separated scopes; all scopes if omitted
+    scopes: Option<String>,
+    /// Seconds until expiry; never expires if omitted
+    expires_in: Option<i64>,
+}
+
+impl Schema for ApiKeyCreationDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 64 },
+                "scopes": { "type": "string", "description": "Space-separated scopes; all scopes if omitted" },
+                "expires_in": { "type": "integer", "minimum": 1, "description": "Seconds until expiry; never expires if omitted" }
+            },
+            "required": ["name"]
+        })
+    }
+}
+
+impl ApiKeyCreationDTO {
+    fn scopes(&self) -> Option<Vec<String>> {
+        self.scopes.as_ref()
+            .map(|scopes| scopes.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+impl Validate for ApiKeyCreationDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.name.is_empty() && self.name.len() <= 64, "name", "must be 1 to 64 bytes");
+        errors.check(self.expires_in.is_none_or(|expires_in| expires_in > 0), "expires_in", "must be positive");
+        errors.check(
+            self.scopes().is_none_or(|scopes| scopes.iter().all(|scope| SCOPES.contains(&scope.as_str()))),
+            "scopes", "unknown scope");
+    }
+}
+
+impl ApiKeysRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { api_key_route: ApiKeyRoute { client: client.clone() }, client }
+    }
+}
+
+impl Display for ApiKeysRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::api_keys::ApiKeysRoute")
+    }
+}
+
+impl Route for ApiKeysRoute {
+    fn name(&self) -> &str {
+        "keys"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.api_key_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS api_keys (
+                prefix       TEXT PRIMARY KEY,
+                account      TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                name         TEXT NOT NULL,
+                hash         TEXT NOT NULL,
+                scopes       TEXT[],
+                expires_at   TIMESTAMPTZ,
+                created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                last_used_at TIMESTAMPTZ
+            );
+            CREATE INDEX IF NOT EXISTS api_keys_account ON api_keys (account);
+            "#).await?;
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.list(req))
+            .describe(Operation::new("API keys of the authorized account")
+                .authorized()
+                .json(200, "The keys, without their secrets", serde_json::json!({ "type": "array", "items": ApiKey::schema() })))
+            .post(|req| self.create(req))
+            .describe(Operation::new("Create an API key")
+                .authorized()
+                .body::<ApiKeyCreationDTO>()
+                .json(201, "The key, which is never shown again", serde_json::json!({
+                    "type": "object",
+                    "properties": { "key": { "type": "string" } },
+                    "required": ["key"]
+                })))
+    }
+}
+
+fn json(status: StatusCode, json: serde_json::Value) -> Response<ResponseBody> {
+    new_response()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(full(Bytes::from(json.to_string())))
+        .unwrap()
+}
+
+impl ApiKeysRoute {
+    fn list(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            // API keys can't manage API keys
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+
+            let keys = serde_json::to_value(ApiKey::list(authenticated.account().id(), &self.client).await?)?;
+
+            Ok(authenticated.respond(json(StatusCode::OK, keys)))
+        })
+    }
+
+    fn create(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let device = Device::from(&req);
+            let creation = match extract::body::<ApiKeyCreationDTO>(req).await {
+                Ok(creation) => creation,
+                Err(e) => return Ok(authenticated.respond(e))
+            };
+
+            let scopes = creation.scopes();
+            let expires_at = creation.expires_in
+                .and_then(TimeDelta::try_seconds)
+                .map(|lifetime| chrono::offset::Utc::now() + lifetime);
+            let key = ApiKey::create(account.id(), &creation.name, scopes, expires_at, &self.client).await?;
+            Audit::record_by(AuditEvent::ApiKeyCreated, Some(account.id()), Some(account.id()), &device, prefix_of(&key), &self.client).await;
+
+            Ok(authenticated.respond(json(StatusCode::CREATED, serde_json::json!({ "key": key }))))
+        })
+    }
+}
+
+impl Display for ApiKeyRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::api_keys::ApiKeyRoute")
+    }
+}
+
+impl Route for ApiKeyRoute {
+    fn name(&self) -> &str {
+        "{prefix}"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .delete(|req| self.revoke(req))
+            .describe(Operation::new("Revoke an API key")
+                .authorized()
+                .response(204, "Revoked")
+                .response(404, "No such key of the account"))
+    }
+}
+
+impl ApiKeyRoute {
+    fn revoke(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let prefix: String = PathParams::of(&req).get("prefix")?;
+            let response = if ApiKey::revoke(account.id(), &prefix, &self.client).await? {
+                Audit::record_by(AuditEvent::ApiKeyRevoked, Some(account.id()), Some(account.id()), &Device::from(&req), Some(&prefix), &self.client).await;
+                new_response()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(full(Bytes::new()))
+                    .unwrap()
+            } else {
+                ApiError::NotFound.into_response()
+            };
+
+            Ok(authenticated.respond(response))
+        })
+    }
+}
@@ -1,23 +1,79 @@
 use crate::credentials::basic::BasicAuth;
-use crate::credentials::tokens::AccessToken;
-use crate::response::new_response;
-use crate::route::{FutureAction, FuturePreparation, Route};
-use crate::routes::account::AccountRow;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
-use hyper::{Method, Request, StatusCode};
+use crate::credentials::login::{Authentication, Login};
+use crate::credentials::session::Device;
+use crate::credentials::tokens::{AccessToken, ChallengeToken};
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::request::client_addr;
+use crate::response::ResponseBody;
+use crate::middleware::rate_limit::RateLimit;
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, Middleware, Route};
+use hyper::body::Incoming;
+use hyper::header::AUTHORIZATION;
+use hyper::{Request, Response};
+use serde::Deserialize;
+use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 use tokio_postgres::Client;
 
 pub struct LoginRoute {
+    totp_route: LoginTotpRoute,
+    rate_limit: Arc<RateLimit>,
     client: Arc<Client>
 }
 
+/// Second step of login for accounts with TOTP enabled
+pub struct LoginTotpRoute {
+    client: Arc<Client>
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginTotpDTO {
+    challenge_token: String,
+    code: String,
+}
+
+impl Validate for LoginTotpDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.challenge_token.is_empty(), "challenge_token", "must not be empty");
+        errors.check(!self.code.is_empty(), "code", "must not be empty");
+    }
+}
+
+impl Schema for LoginTotpDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "challenge_token": { "type": "string" },
+                "code": { "type": "string", "description": "TOTP or recovery code" }
+            },
+            "required": ["challenge_token", "code"]
+        })
+    }
+}
+
 impl LoginRoute {
     pub fn new(client: Arc<Client>) -> Self {
-        Self { client }
+        Self { totp_route: LoginTotpRoute::new(client.clone()), rate_limit: Arc::new(RateLimit::from_env()), client }
+    }
+}
+
+/// The same response for every failure of either step,
+/// so that it doesn't tell whether the account exists, nor why the second factor failed
+fn unauthorized() -> Response<ResponseBody> {
+    ApiError::Unauthorized { challenge: "Basic realm=\"word-chain\"" }.into_response()
+}
+
+/// Response of a step of [`Login`], which sets tokens as cookies
+fn respond(authentication: Authentication) -> Result<Response<ResponseBody>, Box<dyn Error>> {
+    match authentication {
+        Authentication::Authorized(pair) => Ok(pair.into_cookies()),
+        Authentication::Challenged(challenge) => challenge.into_json(),
+        Authentication::Throttled(retry_after) => Ok(ApiError::RateLimited(retry_after as u64).into_response()),
+        Authentication::Denied => Ok(unauthorized())
     }
 }
 
@@ -33,71 +89,176 @@ impl Route for LoginRoute {
     }
 
     fn children(&self) -> Vec<&dyn Route> {
-        vec![]
+        vec![&self.totp_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS refresh_tokens (
+                id                  TEXT PRIMARY KEY,
+                family              TEXT NOT NULL,
+                account             TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                expires_at          TIMESTAMPTZ NOT NULL,
+                consumed            BOOLEAN NOT NULL DEFAULT FALSE,
+                revoked             BOOLEAN NOT NULL DEFAULT FALSE,
+                consumed_at         TIMESTAMPTZ,
+                consumed_address    TEXT,
+                consumed_user_agent TEXT,
+                successor           TEXT
+            );
+            CREATE INDEX IF NOT EXISTS refresh_tokens_family ON refresh_tokens (family);
+            CREATE TABLE IF NOT EXISTS challenges (
+                id         TEXT PRIMARY KEY,
+                account    TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sessions (
+                id           TEXT PRIMARY KEY,
+                account      TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                user_agent   TEXT,
+                address      TEXT,
+                created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            CREATE INDEX IF NOT EXISTS sessions_account ON sessions (account);
+            CREATE TABLE IF NOT EXISTS login_failures (
+                kind         TEXT NOT NULL,
+                subject      TEXT NOT NULL,
+                failures     INTEGER NOT NULL,
+                last_failure TIMESTAMPTZ NOT NULL,
+                locked_until TIMESTAMPTZ,
+                PRIMARY KEY (kind, subject)
+            );
+            CREATE TABLE IF NOT EXISTS revocations (
+                kind       TEXT NOT NULL,
+                subject    TEXT NOT NULL,
+                revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (kind, subject)
+            );
+            "#).await?;
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
     }
 
-    fn up(&self) -> FuturePreparation {
-        Box::pin(async { Ok({}) })
+    fn dependencies(&self) -> Vec<&str> {
+        vec!["word_chain::routes::account::AccountRoute"]
     }
 
-    fn down(&self) -> FuturePreparation {
-        Box::pin(async { Ok({}) })
+    // NOTE: Also covers the second step, so that TOTP codes can't be brute-forced
+    fn middleware(&self) -> Vec<Arc<dyn Middleware>> {
+        vec![self.rate_limit.clone()]
     }
 
-    fn map(&self, req: Request<Incoming>) -> FutureAction {
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .post(|req| self.login(req))
+            .describe(Operation::new("Sign in; tokens are set as cookies")
+                .basic()
+                .response(200, "Signed in")
+                .json(202, "Second factor is required", ChallengeToken::schema())
+                .response(401, "Wrong id or password")
+                .response(429, "Locked out until `Retry-After`"))
+            .delete(|req| self.logout(req))
+            .describe(Operation::new("Sign out, revoking the session")
+                .authorized()
+                .response(200, "Signed out; token cookies are cleared"))
+    }
+}
+
+impl LoginRoute {
+    fn login(&self, req: Request<Incoming>) -> FutureAction<'_> {
         Box::pin(async move {
-            match req.method() {
-                &Method::POST => {
-                    let auth_str = match req.headers().get(AUTHORIZATION).map(|v| v.to_str()) {
-                        Some(Ok(s)) => s,
-                        _ => return Ok(new_response()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Full::from(Bytes::new()))
-                            .unwrap())
-                    };
-
-                    let auth = match BasicAuth::from(auth_str) {
-                        Some(auth) => auth,
-                        None => return Ok(new_response()
-                            .status(StatusCode::UNAUTHORIZED)
-                            .header(WWW_AUTHENTICATE, "Basic realm=\"malformed\"")
-                            .body(Full::from(Bytes::new()))
-                            .unwrap())
-                    };
-
-                    let account = match self.client.query_one(
-                        "SELECT * FROM accounts WHERE id = $1",
-                        &[&auth.id()]).await {
-                        Ok(row) => AccountRow::from(row),
-                        Err(_) => return Ok(new_response()
-                            .status(StatusCode::UNAUTHORIZED)
-                            .header(WWW_AUTHENTICATE, "Basic realm=\"account not found\"")
-                            .body(Full::from(Bytes::new()))
-                            .unwrap())
-                    };
-
-                    let passhash = account.salt().salt(auth.password());
-                    if account.passhash() != passhash {
-                        return Ok(new_response()
-                            .status(StatusCode::UNAUTHORIZED)
-                            .header(WWW_AUTHENTICATE, "Basic realm=\"password mismatched\"")
-                            .body(Full::from(Bytes::new()))
-                            .unwrap())
-                    };
-
-                    let response = match AccessToken::authorize(account.id()).await {
-                        Ok(response) => response,
-                        Err(e) => return Ok(e)
-                    };
-
-                    Ok(response)
+            let auth_str = match req.headers().get(AUTHORIZATION).map(|v| v.to_str()) {
+                Some(Ok(s)) => s,
+                _ => {
+                    let mut errors = FieldErrors::default();
+                    errors.add("Authorization", "must be given as `Basic` credentials");
+                    return Ok(errors.into_response());
                 }
+            };
+
+            let auth = match BasicAuth::from(auth_str) {
+                Some(auth) => auth,
+                None => return Ok(unauthorized())
+            };
+
+            let authentication = Login::password(auth.id(), auth.password(), client_addr(&req), &Device::from(&req), &self.client).await?;
+
+            respond(authentication)
+        })
+    }
+
+    fn logout(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let response = match AccessToken::deauthorize(&req, &self.client).await {
+                Ok(response) => response,
+                Err(e) => return Ok(e)
+            };
+
+            Ok(response)
+        })
+    }
+}
+
+impl LoginTotpRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+impl Display for LoginTotpRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::login::LoginTotpRoute")
+    }
+}
+
+impl Route for LoginTotpRoute {
+    fn name(&self) -> &str {
+        "totp"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .post(|req| self.verify(req))
+            .describe(Operation::new("Complete sign-in with the second factor")
+                .body::<LoginTotpDTO>()
+                .response(200, "Signed in; tokens are set as cookies")
+                .response(401, "Invalid challenge or code, which are not told apart")
+                .response(429, "Locked out until `Retry-After`"))
+    }
+}
+
+impl LoginTotpRoute {
+    fn verify(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let addr = client_addr(&req);
+            let device = Device::from(&req);
+            let dto = match extract::body::<LoginTotpDTO>(req).await {
+                Ok(dto) => dto,
+                Err(e) => return Ok(e)
+            };
+
+            let authentication = Login::second_factor(&dto.challenge_token, &dto.code, addr, &device, &self.client).await?;
 
-                _ => Ok(new_response()
-                    .status(StatusCode::METHOD_NOT_ALLOWED)
-                    .body(Full::from(Bytes::new()))
-                    .unwrap()),
-            }
+            respond(authentication)
         })
     }
 }
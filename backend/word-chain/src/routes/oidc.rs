@@ -0,0 +1,446 @@
+use crate::audit::{Audit, AuditEvent, LoginMethod};
+use crate::credentials::oidc::{Authorization, OidcProvider};
+use crate::credentials::session::Device;
+use crate::credentials::tokens::{flow_cookie, AccessToken, ChallengeToken};
+use crate::credentials::totp::SecondFactor;
+use crate::encrypt::constant_time_eq;
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::keys::{keys, KeyPurpose};
+use crate::response::{full, merge_headers, new_response, ResponseBody};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, PathParams, Route};
+use hyper::body::{Bytes, Incoming};
+use chrono::TimeDelta;
+use cookie::Cookie;
+use hmac::{Hmac, Mac};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// "Sign in with X" of OpenID Connect; see [`OidcProvider`] for configuration.
+///
+/// - `GET /oidc/{provider}/login` redirects to the provider.
+/// - `POST /oidc/{provider}/login` returns the URL of the provider, to link the identity to the authorized account.
+/// - `GET /oidc/{provider}/callback` logs in the account linked with the identity,
+///   creating `{provider}:{subject}` account for unknown identities.
+pub struct OidcRoute {
+    provider_route: OidcProviderRoute,
+    client: Arc<Client>,
+}
+
+pub struct OidcProviderRoute {
+    login_route: OidcLoginRoute,
+    callback_route: OidcCallbackRoute,
+}
+
+pub struct OidcLoginRoute {
+    client: Arc<Client>,
+}
+
+pub struct OidcCallbackRoute {
+    client: Arc<Client>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackDTO {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+impl Validate for CallbackDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.state.is_empty(), "state", "must not be empty");
+    }
+}
+
+impl Schema for CallbackDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "state": { "type": "string" },
+                "code": { "type": "string" },
+                "error": { "type": "string" }
+            },
+            "required": ["state"]
+        })
+    }
+}
+
+fn provider_of(req: &Request<Incoming>) -> Option<&'static OidcProvider> {
+    PathParams::of(req).get::<String>("provider").ok().and_then(|name| OidcProvider::find(&name))
+}
+
+/// Cookie of the MAC of `state`, which binds a flow to the browser that started it
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_EXPIRES: TimeDelta = TimeDelta::minutes(10);
+
+/// Value of [`STATE_COOKIE`], keyed so that it can't be made without the server
+fn state_mac(state: &str) -> String {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(keys().subkey(KeyPurpose::Cookie)).unwrap();
+    mac.update(state.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Denial of sign-in by the provider; the session it would start is of cookies
+fn unauthorized() -> Response<ResponseBody> {
+    ApiError::Unauthorized { challenge: "Cookie" }.into_response()
+}
+
+impl OidcRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self {
+            provider_route: OidcProviderRoute {
+                login_route: OidcLoginRoute { client: client.clone() },
+                callback_route: OidcCallbackRoute { client: client.clone() },
+            },
+            client,
+        }
+    }
+}
+
+impl Display for OidcRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::oidc::OidcRoute")
+    }
+}
+
+impl Route for OidcRoute {
+    fn name(&self) -> &str {
+        "oidc"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.provider_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS account_identities (
+                provider TEXT NOT NULL,
+                subject  TEXT NOT NULL,
+                account  TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                PRIMARY KEY (provider, subject)
+            );
+            CREATE TABLE IF NOT EXISTS oidc_states (
+                state        TEXT PRIMARY KEY,
+                provider     TEXT NOT NULL,
+                verifier     TEXT NOT NULL,
+                nonce        TEXT NOT NULL,
+                link_account TEXT REFERENCES accounts (id) ON DELETE CASCADE,
+                created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#).await?;
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        vec!["word_chain::routes::account::AccountRoute"]
+    }
+}
+
+impl Display for OidcProviderRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::oidc::OidcProviderRoute")
+    }
+}
+
+impl Route for OidcProviderRoute {
+    fn name(&self) -> &str {
+        "{provider}"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.login_route, &self.callback_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Display for OidcLoginRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::oidc::OidcLoginRoute")
+    }
+}
+
+impl Route for OidcLoginRoute {
+    fn name(&self) -> &str {
+        "login"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.redirect(req))
+            .describe(Operation::new("Sign in with the provider")
+                .response(303, "Redirected to the provider")
+                .response(404, "No such provider"))
+            .post(|req| self.link(req))
+            .describe(Operation::new("Link an identity of the provider to the authorized account")
+                .authorized()
+                .json(200, "URL of the provider to be visited", serde_json::json!({
+                    "type": "object",
+                    "properties": { "url": { "type": "string" } },
+                    "required": ["url"]
+                }))
+                .response(404, "No such provider"))
+    }
+}
+
+impl OidcLoginRoute {
+    /// Starts authorization at `provider`, stored in `oidc_states` and bound to the browser by [`STATE_COOKIE`].
+    /// The identity will be linked to `link_account` if given.
+    async fn start(&self, provider: &OidcProvider, link_account: Option<&str>) -> Result<(Authorization, Cookie<'static>), Box<dyn std::error::Error>> {
+        let authorization = provider.authorize().await?;
+
+        self.client.execute(
+            "DELETE FROM oidc_states WHERE created_at < NOW() - INTERVAL '10 minutes';",
+            &[]).await?;
+        self.client.execute(r#"
+            INSERT INTO oidc_states (state, provider, verifier, nonce, link_account)
+            VALUES ($1, $2, $3, $4, $5);
+            "#,
+            &[&authorization.state, &provider.name(), &authorization.verifier, &authorization.nonce, &link_account]).await?;
+
+        let cookie = flow_cookie(STATE_COOKIE, state_mac(&authorization.state), "/oidc", STATE_EXPIRES);
+        Ok((authorization, cookie))
+    }
+
+    fn redirect(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let provider = match provider_of(&req) {
+                Some(provider) => provider,
+                None => return Err(ApiError::NotFound.into())
+            };
+
+            let (authorization, cookie) = self.start(provider, None).await?;
+
+            Ok(new_response()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, authorization.url)
+                .header(SET_COOKIE, cookie.to_string())
+                .body(full(Bytes::new()))
+                .unwrap())
+        })
+    }
+
+    // NOTE: Linking changes the account, so it is started by POST, which is checked against CSRF
+    fn link(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let provider = match provider_of(&req) {
+                Some(provider) => provider,
+                None => return Err(ApiError::NotFound.into())
+            };
+
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+
+            let (authorization, cookie) = self.start(provider, Some(authenticated.account().id())).await?;
+            let json = serde_json::json!({ "url": authorization.url });
+
+            Ok(authenticated.respond(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-store")
+                .header(SET_COOKIE, cookie.to_string())
+                .body(full(Bytes::from(json.to_string())))
+                .unwrap()))
+        })
+    }
+}
+
+impl OidcCallbackRoute {
+    /// Finds the account linked with the identity, linking or creating one if not found.
+    /// Returns `None` if an account of the same id exists without the link,
+    /// or if the identity to be linked with `link_account` is already linked with another account.
+    async fn resolve_account(&self, provider: &str, subject: &str, link_account: Option<String>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if let Some(row) = self.client.query_opt(
+            "SELECT account FROM account_identities WHERE provider = $1 AND subject = $2;",
+            &[&provider, &subject]).await? {
+            let linked: String = row.get(0);
+
+            // Linking must never sign the user in as another account
+            return Ok(match link_account {
+                Some(account) if account != linked => None,
+                _ => Some(linked)
+            });
+        }
+
+        let account = match link_account {
+            Some(account) => account,
+            None => {
+                // NOTE: `:` can't be used for ids of accounts created with password
+                let account = format!("{}:{}", provider, subject);
+
+                // Password is empty, which never matches with any hash
+                let created = self.client.execute(
+                    "INSERT INTO accounts (id, salt, password) VALUES ($1, '', '') ON CONFLICT DO NOTHING;",
+                    &[&account]).await?;
+                if created != 1 {
+                    return Ok(None);
+                }
+
+                account
+            }
+        };
+
+        self.client.execute(
+            "INSERT INTO account_identities (provider, subject, account) VALUES ($1, $2, $3);",
+            &[&provider, &subject, &account]).await?;
+
+        Ok(Some(account))
+    }
+}
+
+impl Display for OidcCallbackRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::oidc::OidcCallbackRoute")
+    }
+}
+
+impl Route for OidcCallbackRoute {
+    fn name(&self) -> &str {
+        "callback"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.callback(req))
+            .describe(Operation::new("Redirection from the provider")
+                .query::<CallbackDTO>()
+                .response(303, "Signed in; tokens are set as cookies")
+                .json(202, "Second factor is required", ChallengeToken::schema())
+                .response(400, "Unknown state, or started by another browser")
+                .response(401, "Denied by the provider")
+                .response(409, "The identity can't be linked, or is linked with another account"))
+    }
+}
+
+impl OidcCallbackRoute {
+    fn callback(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let provider = match provider_of(&req) {
+                Some(provider) => provider,
+                None => return Err(ApiError::NotFound.into())
+            };
+
+            let callback = match extract::query::<CallbackDTO>(&req) {
+                Ok(callback) => callback,
+                Err(errors) => return Ok(errors.into_response())
+            };
+
+            // The state has to come back to the browser which started the flow,
+            // or anyone could sign a victim into their own account (RFC 6749 §10.12)
+            let bound = extract::cookie(&req, STATE_COOKIE)
+                .is_some_and(|mac| constant_time_eq(&mac, &state_mac(&callback.state)));
+            if !bound {
+                let mut errors = FieldErrors::default();
+                errors.add("state", "was not started by this browser");
+                return Ok(errors.into_response());
+            }
+
+            // State is consumed even if the provider returned error
+            let pending = match self.client.query_opt(r#"
+                DELETE FROM oidc_states
+                WHERE state = $1 AND provider = $2 AND created_at > NOW() - INTERVAL '10 minutes'
+                RETURNING verifier, nonce, link_account;
+                "#,
+                &[&callback.state, &provider.name()]).await? {
+                Some(row) => row,
+                None => {
+                    let mut errors = FieldErrors::default();
+                    errors.add("state", "is unknown or expired");
+                    return Ok(errors.into_response());
+                }
+            };
+
+            let code = match (callback.code, callback.error) {
+                (Some(code), None) => code,
+                _ => return Ok(unauthorized())
+            };
+
+            let identity = match provider.exchange(&code, pending.get(0), pending.get(1)).await {
+                Ok(identity) => identity,
+                Err(e) => {
+                    eprintln!("Failed to sign in with `{}`: {}", provider.name(), e);
+                    return Ok(unauthorized());
+                }
+            };
+
+            let account = match self.resolve_account(provider.name(), &identity.subject, pending.get(2)).await? {
+                Some(account) => account,
+                None => return Err(ApiError::AccountExists.into())
+            };
+
+            // The flow is over, whichever step follows
+            let mut cleared = flow_cookie(STATE_COOKIE, String::new(), "/oidc", STATE_EXPIRES);
+            cleared.make_removal();
+
+            let device = Device::from(&req);
+            if SecondFactor::enabled(&account, &self.client).await? {
+                Audit::record_login(AuditEvent::LoginChallenged, &account, LoginMethod::Oidc(provider.name()), &device, &self.client).await;
+
+                let mut response = ChallengeToken::issue(&account, &self.client).await?.into_json()?;
+                response.headers_mut().append(SET_COOKIE, cleared.to_string().parse().unwrap());
+                return Ok(response);
+            }
+
+            let response = match AccessToken::authorize(&account, &device, &self.client).await {
+                Ok(response) => response,
+                Err(e) => return Ok(e)
+            };
+            Audit::record_login(AuditEvent::LoginSucceeded, &account, LoginMethod::Oidc(provider.name()), &device, &self.client).await;
+
+            let location = std::env::var("OIDC_LOGIN_REDIRECT").unwrap_or("/".to_string());
+            Ok(merge_headers(new_response()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, location)
+                .header(SET_COOKIE, cleared.to_string())
+                .body(full(Bytes::new()))
+                .unwrap(), response))
+        })
+    }
+}
@@ -0,0 +1,71 @@
+use crate::openapi::Operation;
+use crate::response::{full, new_response};
+use crate::route::{FutureAction, FuturePreparation, Methods, Route};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::Request;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// OpenAPI document of all routes (GET `/openapi.json`)
+pub struct OpenApiRoute {
+    document: OnceLock<String>,
+}
+
+impl OpenApiRoute {
+    pub fn new() -> Self {
+        Self { document: OnceLock::new() }
+    }
+
+    /// Serves `document` from now on; the first one is kept, since routes never change
+    pub fn publish(&self, document: serde_json::Value) {
+        self.document.get_or_init(|| document.to_string());
+    }
+}
+
+impl Display for OpenApiRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::openapi::OpenApiRoute")
+    }
+}
+
+impl Route for OpenApiRoute {
+    fn name(&self) -> &str {
+        "openapi.json"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.show(req))
+            .describe(Operation::new("OpenAPI document of this API")
+                .response(200, "OpenAPI 3.1 document"))
+    }
+}
+
+impl OpenApiRoute {
+    fn show(&self, _req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let document = match self.document.get() {
+                Some(document) => document.clone(),
+                None => return Err("OpenAPI document is not published yet".into())
+            };
+
+            Ok(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(Bytes::from(document)))
+                .unwrap())
+        })
+    }
+}
@@ -1,24 +1,47 @@
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, StatusCode};
 use tokio_postgres::Client;
+use crate::middleware::authorize::Authorize;
+use crate::middleware::cors::Cors;
+use crate::middleware::logger::Logger;
+use crate::middleware::recover::Recover;
+use crate::middleware::timeout::Timeout;
+use crate::openapi;
 use crate::routes::account::AccountRoute;
-use crate::response::new_response;
-use crate::route::{FutureAction, FuturePreparation, Route};
+use crate::routes::admin::AdminRoute;
+use crate::route::{FuturePreparation, Middleware, Route};
 use crate::routes::login::LoginRoute;
+use crate::routes::oidc::OidcRoute;
+use crate::routes::openapi::OpenApiRoute;
+use crate::routes::token::TokenRoute;
 
 pub struct RootRoute {
     account_route: AccountRoute,
-    login_route: LoginRoute
+    login_route: LoginRoute,
+    token_route: TokenRoute,
+    oidc_route: OidcRoute,
+    admin_route: AdminRoute,
+    openapi_route: OpenApiRoute,
+    middleware: Vec<Arc<dyn Middleware>>
 }
 
 impl RootRoute {
     pub fn new(client: &Arc<Client>) -> RootRoute {
         Self {
             account_route: AccountRoute::new(client.clone()),
-            login_route: LoginRoute::new(client.clone())
+            login_route: LoginRoute::new(client.clone()),
+            token_route: TokenRoute::new(client.clone()),
+            oidc_route: OidcRoute::new(client.clone()),
+            admin_route: AdminRoute::new(client.clone()),
+            openapi_route: OpenApiRoute::new(),
+            // Outermost first; errors are recovered inside of logging and CORS
+            middleware: vec![
+                Arc::new(Logger),
+                Arc::new(Cors::from_env()),
+                Arc::new(Timeout::from_env()),
+                Arc::new(Recover),
+                Arc::new(Authorize::new(client.clone()))
+            ]
         }
     }
 }
@@ -34,23 +57,27 @@ impl Route for RootRoute {
     fn children(&self) -> Vec<&dyn Route> {
         vec![
             &self.account_route,
-            &self.login_route
+            &self.login_route,
+            &self.token_route,
+            &self.oidc_route,
+            &self.admin_route,
+            &self.openapi_route
         ]
     }
 
-    fn up(&self) -> FuturePreparation
-    { Box::pin(async { Ok(()) }) }
-
-    fn down(&self) -> FuturePreparation
-    { Box::pin(async { Ok(()) }) }
-
-    fn map(&self, _req: Request<Incoming>) -> FutureAction
+    // NOTE: The whole tree is known here, since the root is up first
+    fn up(&self) -> FuturePreparation<'_>
     {
         Box::pin(async move {
-            Ok(new_response()
-                .status(StatusCode::NOT_FOUND)
-                .body(Full::from(Bytes::new()))
-                .unwrap())
+            self.openapi_route.publish(openapi::document(self));
+            Ok(())
         })
     }
+
+    fn down(&self) -> FuturePreparation<'_>
+    { Box::pin(async { Ok(()) }) }
+
+    fn middleware(&self) -> Vec<Arc<dyn Middleware>> {
+        self.middleware.clone()
+    }
 }
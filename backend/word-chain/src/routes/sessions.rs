@@ -0,0 +1,145 @@
+use crate::audit::{Audit, AuditEvent};
+use crate::credentials::session::{Device, Session};
+use crate::credentials::tokens::{AccessToken, RefreshToken, Token};
+use crate::error::ApiError;
+use crate::extract;
+use crate::response::{full, new_response};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, PathParams, Route};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, StatusCode};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Logged-in devices of the authorized account (GET)
+pub struct SessionsRoute {
+    session_route: SessionRoute,
+    client: Arc<Client>,
+}
+
+/// Remote sign-out of a device (DELETE `/account/sessions/{id}`)
+pub struct SessionRoute {
+    client: Arc<Client>,
+}
+
+impl SessionsRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { session_route: SessionRoute { client: client.clone() }, client }
+    }
+}
+
+impl Display for SessionsRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::sessions::SessionsRoute")
+    }
+}
+
+impl Route for SessionsRoute {
+    fn name(&self) -> &str {
+        "sessions"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![&self.session_route]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .get(|req| self.list(req))
+            .describe(Operation::new("Logged-in devices of the authorized account")
+                .authorized()
+                .json(200, "The sessions", serde_json::json!({ "type": "array", "items": Session::schema() })))
+    }
+}
+
+impl SessionsRoute {
+    fn list(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+
+            // Access token has been verified above; it is read again only for its family
+            let current = AccessToken::from_request(&req)?;
+            let sessions = Session::list(authenticated.account().id(), current.family(), &self.client).await?;
+
+            Ok(authenticated.respond(new_response()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-store")
+                .body(full(Bytes::from(serde_json::to_string(&sessions)?)))
+                .unwrap()))
+        })
+    }
+}
+
+impl Display for SessionRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::sessions::SessionRoute")
+    }
+}
+
+impl Route for SessionRoute {
+    fn name(&self) -> &str {
+        "{id}"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .delete(|req| self.revoke(req))
+            .describe(Operation::new("Sign out a device")
+                .authorized()
+                .response(204, "Revoked")
+                .response(404, "No such session of the account"))
+    }
+}
+
+impl SessionRoute {
+    fn revoke(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let id: String = PathParams::of(&req).get("id")?;
+
+            // Sessions of the others are not found, rather than forbidden
+            let response = if Session::owned(&id, account.id(), &self.client).await? {
+                RefreshToken::revoke_family(&id, &self.client).await?;
+                Audit::record(AuditEvent::SessionRevoked, Some(account.id()), &Device::from(&req), Some(&id), &self.client).await;
+                new_response()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(full(Bytes::new()))
+                    .unwrap()
+            } else {
+                ApiError::NotFound.into_response()
+            };
+
+            Ok(authenticated.respond(response))
+        })
+    }
+}
@@ -0,0 +1,194 @@
+use crate::credentials::login::{Authentication, Login};
+use crate::credentials::session::Device;
+use crate::credentials::tokens::{ChallengeToken, TokenPair};
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::request::client_addr;
+use crate::response::{full, new_response, ResponseBody};
+use crate::middleware::rate_limit::RateLimit;
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, Middleware, Route};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Token endpoint for non-browser clients, which returns tokens as JSON instead of cookies.
+/// Supports `password` and `refresh_token` grants of RFC 6749,
+/// and `totp` grant which completes `password` grant of accounts with second factor.
+pub struct TokenRoute {
+    rate_limit: Arc<RateLimit>,
+    client: Arc<Client>
+}
+
+/// Fields of each grant, told apart by `grant_type`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+enum TokenRequestDTO {
+    Password { username: String, password: String },
+    RefreshToken { refresh_token: String },
+    Totp { challenge_token: String, code: String },
+}
+
+impl Validate for TokenRequestDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        match self {
+            TokenRequestDTO::Password { username, password } => {
+                errors.check(!username.is_empty(), "username", "must not be empty");
+                errors.check(!password.is_empty(), "password", "must not be empty");
+            }
+            TokenRequestDTO::RefreshToken { refresh_token } => {
+                errors.check(!refresh_token.is_empty(), "refresh_token", "must not be empty");
+            }
+            TokenRequestDTO::Totp { challenge_token, code } => {
+                errors.check(!challenge_token.is_empty(), "challenge_token", "must not be empty");
+                errors.check(!code.is_empty(), "code", "must not be empty");
+            }
+        }
+    }
+}
+
+impl Schema for TokenRequestDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "oneOf": [
+                {
+                    "type": "object",
+                    "properties": {
+                        "grant_type": { "const": "password" },
+                        "username": { "type": "string" },
+                        "password": { "type": "string" }
+                    },
+                    "required": ["grant_type", "username", "password"]
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "grant_type": { "const": "refresh_token" },
+                        "refresh_token": { "type": "string" }
+                    },
+                    "required": ["grant_type", "refresh_token"]
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "grant_type": { "const": "totp" },
+                        "challenge_token": { "type": "string" },
+                        "code": { "type": "string", "description": "TOTP or recovery code" }
+                    },
+                    "required": ["grant_type", "challenge_token", "code"]
+                }
+            ]
+        })
+    }
+}
+
+impl TokenRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { rate_limit: Arc::new(RateLimit::from_env()), client }
+    }
+}
+
+/// Error response of RFC 6749 §5.2, for credentials which are not granted
+fn token_error(error: &str) -> Response<ResponseBody> {
+    new_response()
+        .status(StatusCode::BAD_REQUEST)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(full(Bytes::from(format!(r#"{{"error":"{}"}}"#, error))))
+        .unwrap()
+}
+
+impl Display for TokenRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::token::TokenRoute")
+    }
+}
+
+impl Route for TokenRoute {
+    fn name(&self) -> &str {
+        "token"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn middleware(&self) -> Vec<Arc<dyn Middleware>> {
+        vec![self.rate_limit.clone()]
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .post(|req| self.grant(req))
+            .describe(Operation::new("Issue tokens as JSON, for non-browser clients (RFC 6749)")
+                .body::<TokenRequestDTO>()
+                .json(200, "Granted", TokenPair::schema())
+                .json(202, "Second factor is required, by `totp` grant", ChallengeToken::schema())
+                .response(400, "Invalid request, or `invalid_grant` error of RFC 6749")
+                .response(429, "Locked out until `Retry-After`"))
+    }
+}
+
+impl TokenRoute {
+    fn grant(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let addr = client_addr(&req);
+            let device = Device::from(&req);
+            let request = match extract::body::<TokenRequestDTO>(req).await {
+                Ok(request) => request,
+                Err(e) => return Ok(e)
+            };
+
+            let grant = match request {
+                TokenRequestDTO::Password { username, password } =>
+                    Login::password(&username, &password, addr, &device, &self.client).await?,
+                TokenRequestDTO::RefreshToken { refresh_token } => match TokenPair::refresh(&refresh_token, &device, &self.client).await? {
+                    Some(pair) => Authentication::Authorized(pair),
+                    None => Authentication::Denied
+                },
+                TokenRequestDTO::Totp { challenge_token, code } =>
+                    Login::second_factor(&challenge_token, &code, addr, &device, &self.client).await?
+            };
+
+            match grant {
+                Authentication::Authorized(pair) => Ok(pair.into_json()),
+                Authentication::Challenged(challenge) => challenge.into_json(),
+                Authentication::Throttled(retry_after) => Err(ApiError::RateLimited(retry_after as u64).into()),
+                Authentication::Denied => Ok(token_error("invalid_grant"))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Result<TokenRequestDTO, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_bytes(data)
+    }
+
+    #[test]
+    fn test_grants() {
+        assert!(matches!(
+            parse(b"grant_type=password&username=bot&password=secret"),
+            Ok(TokenRequestDTO::Password { username, .. }) if username == "bot"));
+        assert!(matches!(
+            parse(b"grant_type=refresh_token&refresh_token=token"),
+            Ok(TokenRequestDTO::RefreshToken { .. })));
+        assert!(parse(b"grant_type=password&username=bot").is_err());
+        assert!(parse(b"grant_type=client_credentials").is_err());
+    }
+}
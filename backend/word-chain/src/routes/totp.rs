@@ -0,0 +1,225 @@
+use crate::audit::{Audit, AuditEvent};
+use crate::credentials::session::Device;
+use crate::credentials::throttle::LoginThrottle;
+use crate::credentials::totp::SecondFactor;
+use crate::error::ApiError;
+use crate::extract::{self, FieldErrors, Validate};
+use crate::request::client_addr;
+use crate::response::{full, new_response, ResponseBody};
+use crate::openapi::{Operation, Schema};
+use crate::route::{FutureAction, FuturePreparation, Methods, Route};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+/// Enrollment (POST), confirmation (PUT) and disabling (DELETE) of TOTP for the authorized account
+pub struct TotpRoute {
+    client: Arc<Client>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TotpCodeDTO {
+    code: String,
+}
+
+impl Validate for TotpCodeDTO {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.code.is_empty(), "code", "must not be empty");
+    }
+}
+
+impl Schema for TotpCodeDTO {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": { "code": { "type": "string" } },
+            "required": ["code"]
+        })
+    }
+}
+
+impl TotpRoute {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+impl Display for TotpRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "word_chain::routes::totp::TotpRoute")
+    }
+}
+
+impl Route for TotpRoute {
+    fn name(&self) -> &str {
+        "totp"
+    }
+
+    fn children(&self) -> Vec<&dyn Route> {
+        vec![]
+    }
+
+    fn up(&self) -> FuturePreparation<'_> {
+        Box::pin(async move {
+            self.client.batch_execute(r#"
+            CREATE TABLE IF NOT EXISTS totp (
+                account   TEXT PRIMARY KEY REFERENCES accounts (id) ON DELETE CASCADE,
+                secret    TEXT NOT NULL,
+                confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+                last_step BIGINT NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+                account TEXT NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                hash    TEXT NOT NULL,
+                used    BOOLEAN NOT NULL DEFAULT FALSE,
+                PRIMARY KEY (account, hash)
+            );
+            "#).await?;
+
+            Ok(())
+        })
+    }
+
+    fn down(&self) -> FuturePreparation<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn methods(&self) -> Methods<'_> {
+        Methods::new()
+            .post(|req| self.enroll(req))
+            .describe(Operation::new("Start enrollment of TOTP")
+                .authorized()
+                .json(200, "Secret to be confirmed", serde_json::json!({
+                    "type": "object",
+                    "properties": { "secret": { "type": "string" }, "uri": { "type": "string" } },
+                    "required": ["secret", "uri"]
+                }))
+                .response(409, "Already enabled"))
+            .put(|req| self.confirm(req))
+            .describe(Operation::new("Confirm enrollment with a code, enabling TOTP")
+                .authorized()
+                .body::<TotpCodeDTO>()
+                .json(200, "Enabled", serde_json::json!({
+                    "type": "object",
+                    "properties": { "recovery_codes": { "type": "array", "items": { "type": "string" } } },
+                    "required": ["recovery_codes"]
+                }))
+                .response(403, "Code mismatched"))
+            .delete(|req| self.disable(req))
+            .describe(Operation::new("Disable TOTP with a code")
+                .authorized()
+                .body::<TotpCodeDTO>()
+                .response(204, "Disabled")
+                .response(403, "Code mismatched")
+                .response(429, "Locked out until `Retry-After`"))
+    }
+}
+
+/// Code of [`TotpCodeDTO`] in the body of `req`
+async fn read_code(req: Request<Incoming>) -> Result<String, Response<ResponseBody>> {
+    Ok(extract::body::<TotpCodeDTO>(req).await?.code)
+}
+
+fn respond(status: StatusCode, json: Option<serde_json::Value>) -> Response<ResponseBody> {
+    match json {
+        Some(json) => new_response()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-store")
+            .body(full(Bytes::from(json.to_string())))
+            .unwrap(),
+        None => new_response()
+            .status(status)
+            .body(full(Bytes::new()))
+            .unwrap()
+    }
+}
+
+impl TotpRoute {
+    fn enroll(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let response = match SecondFactor::enroll(account.id(), &self.client).await? {
+                Some(totp) => respond(StatusCode::OK, Some(serde_json::json!({
+                    "secret": totp.base32(),
+                    "uri": totp.uri(account.id()),
+                }))),
+                None => ApiError::SecondFactorEnabled.into_response()
+            };
+
+            Ok(authenticated.respond(response))
+        })
+    }
+
+    fn confirm(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let device = Device::from(&req);
+            let code = match read_code(req).await {
+                Ok(code) => code,
+                Err(e) => return Ok(authenticated.respond(e))
+            };
+
+            let confirmed = SecondFactor::confirm(account.id(), &code, &self.client).await?;
+            let response = match confirmed {
+                Some(recovery_codes) => {
+                    Audit::record_by(AuditEvent::SecondFactorEnabled, Some(account.id()), Some(account.id()), &device, None, &self.client).await;
+                    respond(StatusCode::OK, Some(serde_json::json!({
+                        "recovery_codes": recovery_codes
+                    })))
+                }
+                None => ApiError::Forbidden.into_response()
+            };
+
+            Ok(authenticated.respond(response))
+        })
+    }
+
+    fn disable(&self, req: Request<Incoming>) -> FutureAction<'_> {
+        Box::pin(async move {
+            let authenticated = match extract::authenticated(&req, &self.client).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => return Ok(e)
+            };
+            let account = authenticated.account();
+
+            let addr = client_addr(&req);
+            let device = Device::from(&req);
+            let code = match read_code(req).await {
+                Ok(code) => code,
+                Err(e) => return Ok(authenticated.respond(e))
+            };
+
+            // Disabling requires second factor too, so that a stolen session can't remove it;
+            // failures are throttled as those of login, or the session could guess the code
+            let locked = LoginThrottle::locked(account.id(), addr, &self.client).await?;
+            if let Some(retry_after) = locked {
+                return Ok(authenticated.respond(ApiError::RateLimited(retry_after as u64).into_response()));
+            }
+            let response = if SecondFactor::verify(account.id(), &code, &self.client).await? {
+                SecondFactor::disable(account.id(), &self.client).await?;
+                Audit::record_by(AuditEvent::SecondFactorDisabled, Some(account.id()), Some(account.id()), &device, None, &self.client).await;
+                respond(StatusCode::NO_CONTENT, None)
+            } else {
+                LoginThrottle::fail(account.id(), addr, &self.client).await?;
+                ApiError::Forbidden.into_response()
+            };
+
+            Ok(authenticated.respond(response))
+        })
+    }
+}